@@ -0,0 +1,209 @@
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
+
+use argon2::{Argon2, PasswordHasher, PasswordVerifier};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+use crate::states::{ClientState, Outbox, ServerState};
+
+const MAX_FAILED_LOGINS: u32 = 5;
+const LOCKOUT: Duration = Duration::from_secs(300);
+const MAX_PASSWORD_TRIES: u32 = 3;
+
+// Names double as file names for the json store so keep them boring
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 20 && name.chars().all(|a| a.is_ascii_alphanumeric() || a == '_' || a == '-')
+}
+
+// Argon2 is slow on purpose, keep it off the async workers
+pub async fn hash_password(password: String) -> Option<String> {
+    tokio::task::spawn_blocking(move || {
+        Argon2::default().hash_password(password.as_bytes()).ok().map(|a| a.to_string())
+    }).await.ok().flatten()
+}
+
+pub async fn verify_password(password: String, hash: String) -> bool {
+    if hash.is_empty() {
+        return false;
+    }
+    tokio::task::spawn_blocking(move || {
+        Argon2::default().verify_password(password.as_bytes(), hash.as_str()).is_ok()
+    }).await.unwrap_or(false)
+}
+
+struct Failures {
+    count: u32,
+    locked_until: Option<Instant>
+}
+
+// Failed password attempts per account name, shared by every connection
+#[derive(Default)]
+pub struct LoginThrottle {
+    failures: std::sync::Mutex<HashMap<String, Failures>>
+}
+
+impl LoginThrottle {
+    // How long the account stays locked, if it is
+    pub fn locked_for(&self, name: &str) -> Option<Duration> {
+        let failures = self.failures.lock().unwrap();
+        let until = failures.get(name)?.locked_until?;
+        until.checked_duration_since(Instant::now())
+    }
+
+    // Returns how long to stall before letting them try again
+    pub fn record_failure(&self, name: &str) -> Duration {
+        let mut failures = self.failures.lock().unwrap();
+        let entry = failures.entry(name.into()).or_insert(Failures { count: 0, locked_until: None });
+        if entry.locked_until.map(|a| a <= Instant::now()).unwrap_or(false) {
+            entry.count = 0;
+            entry.locked_until = None;
+        }
+        entry.count += 1;
+        if entry.count >= MAX_FAILED_LOGINS {
+            entry.locked_until = Some(Instant::now() + LOCKOUT);
+        }
+        Duration::from_secs(entry.count.min(5) as u64)
+    }
+
+    pub fn clear(&self, name: &str) {
+        self.failures.lock().unwrap().remove(name);
+    }
+
+    // Checks a password against the account's stored hash, counting failures
+    pub async fn check(&self, name: &str, password_hash: &str, password: String) -> Result<(), String> {
+        if let Some(left) = self.locked_for(name) {
+            return Err(format!{"Too many failed attempts, try again in {} seconds.", left.as_secs() + 1});
+        }
+        if verify_password(password, password_hash.into()).await {
+            self.clear(name);
+            return Ok(());
+        }
+        tokio::time::sleep(self.record_failure(name)).await;
+        Err("Wrong password.".into())
+    }
+}
+
+async fn prompt<R>(reader: &mut R, outbox: &Outbox, text: &str) -> Option<String>
+where R: AsyncBufRead + Unpin {
+    outbox.send(text.into()).ok()?;
+    let mut string_input = String::new();
+    if reader.read_line(&mut string_input).await.ok()? == 0 {
+        return None;
+    }
+    Some(string_input.replace(['\r', '\n'], ""))
+}
+
+// Asks for a new password twice
+async fn choose_password<R>(reader: &mut R, outbox: &Outbox) -> Option<String>
+where R: AsyncBufRead + Unpin {
+    for _ in 0..MAX_PASSWORD_TRIES {
+        let password = prompt(reader, outbox, "@DChoose a password:").await?;
+        if password.len() < 4 {
+            outbox.send("@BPasswords need at least 4 characters.".into()).ok()?;
+            continue;
+        }
+        let again = prompt(reader, outbox, "@DType it again:").await?;
+        if password == again {
+            return hash_password(password).await;
+        }
+        outbox.send("@BThose did not match.".into()).ok()?;
+    }
+    None
+}
+
+// The name/password conversation a connection has before it gets to play,
+// None means they gave up or failed too many times and should be dropped
+pub async fn authenticate<R>(reader: &mut R, outbox: &Outbox, server_state: &Arc<ServerState>) -> Option<ClientState>
+where R: AsyncBufRead + Unpin {
+    let name = loop {
+        let name = prompt(reader, outbox, "@DPlease enter your name (letters, numbers, _ and -).").await?;
+        if valid_name(&name) {
+            break name;
+        }
+        outbox.send("@BThat is not a valid name.".into()).ok()?;
+    };
+
+    if server_state.is_playing(&name) {
+        outbox.send("@BThat character is already playing.".into()).ok()?;
+        return None;
+    }
+
+    let existing = match server_state.load_account(&name).await {
+        Ok(existing) => existing,
+        Err(e) => return unreadable(outbox, &name, e)
+    };
+    if let Some(client) = existing {
+        // Accounts from before passwords existed would go to whoever typed the name first
+        if client.password_hash.is_empty() {
+            outbox.send("@BThis character has no password, ask an admin to \\reset_password it.".into()).ok()?;
+            return None;
+        }
+        for _ in 0..MAX_PASSWORD_TRIES {
+            let password = prompt(reader, outbox, "@DPassword:").await?;
+            match server_state.login_throttle.check(&client.name, &client.password_hash, password).await {
+                Ok(()) => {
+                    // The account's own name, not what they typed, is what gets claimed
+                    let client = claim(server_state, outbox, &client.name).await?;
+                    let _ = outbox.send("Welcome back :)".into());
+                    return Some(client);
+                },
+                Err(e) => {
+                    outbox.send(format!{"@B{}", e}).ok()?;
+                    if server_state.login_throttle.locked_for(&name).is_some() {
+                        return None;
+                    }
+                }
+            }
+        }
+        return None;
+    }
+
+    outbox.send("New face...".into()).ok()?;
+    let mut client = ClientState::new(None);
+    client.name = name;
+    client.channels = server_state.channels.auto_join();
+    client.password_hash = choose_password(reader, outbox).await?;
+    // Someone else may have registered it while they were choosing
+    if !server_state.claim_name(&client.name) {
+        outbox.send("@BSomeone just took that name.".into()).ok()?;
+        return None;
+    }
+    match server_state.load_account(&client.name).await {
+        Ok(None) => Some(client),
+        Ok(Some(_)) => {
+            server_state.release_name(&client.name);
+            outbox.send("@BSomeone just took that name.".into()).ok()?;
+            None
+        },
+        Err(e) => {
+            server_state.release_name(&client.name);
+            unreadable(outbox, &client.name, e)
+        }
+    }
+}
+
+// Logged for the admins, the player is only told to go and find one
+fn unreadable(outbox: &Outbox, name: &str, e: std::io::Error) -> Option<ClientState> {
+    eprintln!("Could not load the account {}: {}", name, e);
+    let _ = outbox.send("@BThat character could not be loaded, ask an admin.".into());
+    None
+}
+
+// Takes the name once they have proved who they are, then loads the account again since whoever
+// had it last may have saved since it was first read
+async fn claim(server_state: &Arc<ServerState>, outbox: &Outbox, name: &str) -> Option<ClientState> {
+    if !server_state.claim_name(name) {
+        outbox.send("@BThat character is already playing.".into()).ok()?;
+        return None;
+    }
+    match server_state.load_client(name.into()).await {
+        Ok(Some(client)) => Some(client),
+        Ok(None) => {
+            server_state.release_name(name);
+            None
+        },
+        Err(e) => {
+            server_state.release_name(name);
+            unreadable(outbox, name, e)
+        }
+    }
+}
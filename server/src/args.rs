@@ -0,0 +1,272 @@
+use std::fmt;
+
+use crate::{accounts, commands::COMMANDS, states::RoomAddr};
+
+// Bails out of a command handler with the usage error as its reply
+macro_rules! arg {
+    ($exp:expr) => {
+        match $exp {
+            Ok(a) => a,
+            Err(e) => return e.to_string()
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ArgError {
+    reason: String,
+    usage: &'static str
+}
+
+impl fmt::Display for ArgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.usage.is_empty() {
+            return write!(f, "@B{}", self.reason);
+        }
+        write!(f, "@B{}\nUsage: {}", self.reason, self.usage)
+    }
+}
+
+// Reads a command's arguments left to right. Arguments are split on spaces,
+// "double quotes" keep spaces together and \" or \\ escape inside them.
+// Nothing is tokenized until asked for, so rest() sees the raw text.
+pub struct Args<'a> {
+    input: &'a str,
+    position: usize,
+    usage: &'static str
+}
+
+impl<'a> Args<'a> {
+    // Skips the command word and picks up its usage line from the registry
+    pub fn parse(input: &'a str) -> Self {
+        let word_end = input.find(' ').unwrap_or(input.len());
+        let usage = COMMANDS.find(&input[..word_end]).map(|a| a.usage).unwrap_or("");
+        Self { input, position: word_end, usage }
+    }
+
+    pub fn error(&self, reason: String) -> ArgError {
+        ArgError { reason, usage: self.usage }
+    }
+
+    fn remaining(&self) -> &'a str {
+        &self.input[self.position..]
+    }
+
+    fn skip_spaces(&mut self) {
+        let remaining = self.remaining();
+        self.position += remaining.len() - remaining.trim_start().len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining().trim().is_empty()
+    }
+
+    fn token(&mut self) -> Result<Option<String>, ArgError> {
+        self.skip_spaces();
+        let remaining = self.remaining();
+        if remaining.is_empty() {
+            return Ok(None);
+        }
+
+        if !remaining.starts_with('"') {
+            let end = remaining.find(char::is_whitespace).unwrap_or(remaining.len());
+            self.position += end;
+            return Ok(Some(remaining[..end].into()));
+        }
+
+        let mut token = String::new();
+        let mut chars = remaining.char_indices().skip(1);
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.position += i + 1;
+                    return Ok(Some(token));
+                },
+                '\\' => match chars.next() {
+                    Some((_, escaped)) => token.push(escaped),
+                    None => break
+                },
+                _ => token.push(c)
+            }
+        }
+        Err(self.error("Missing a closing \" quote.".into()))
+    }
+
+    pub fn optional_word(&mut self) -> Result<Option<String>, ArgError> {
+        self.token()
+    }
+
+    pub fn word(&mut self, name: &str) -> Result<String, ArgError> {
+        match self.token()? {
+            Some(word) if !word.is_empty() => Ok(word),
+            _ => Err(self.error(format!{"Missing <{}>.", name}))
+        }
+    }
+
+    // Objects can be called anything, quote them if the name has spaces
+    pub fn object_name(&mut self, name: &str) -> Result<String, ArgError> {
+        self.word(name)
+    }
+
+    // Anything that ends up as a store key or a file name, like players, scripts, skills and templates.
+    // They follow the same rules as account names.
+    pub fn identifier(&mut self, name: &str) -> Result<String, ArgError> {
+        let word = self.word(name)?;
+        self.check_identifier(&word, name)?;
+        Ok(word)
+    }
+
+    // For identifiers that are not a word of their own, like a script name before its contents
+    pub fn check_identifier(&self, word: &str, name: &str) -> Result<(), ArgError> {
+        if !accounts::valid_name(word) {
+            return Err(self.error(format!{"{} is not a valid <{}>, use letters, numbers, _ and -.", word, name}));
+        }
+        Ok(())
+    }
+
+    pub fn room_addr(&mut self, name: &str) -> Result<RoomAddr, ArgError> {
+        self.identifier(name)
+    }
+
+    pub fn integer(&mut self, name: &str) -> Result<i64, ArgError> {
+        let word = self.word(name)?;
+        word.parse::<i64>().map_err(|_| self.error(format!{"<{}> should be a whole number, not {}.", name, word}))
+    }
+
+    // Everything left, as typed. A single quoted string gets unquoted.
+    pub fn optional_rest(&mut self) -> Result<Option<String>, ArgError> {
+        self.skip_spaces();
+        let remaining = self.remaining().trim_end();
+        if remaining.is_empty() {
+            return Ok(None);
+        }
+        if remaining.starts_with('"') {
+            let start = self.position;
+            if let Ok(token) = self.token() {
+                if self.is_empty() {
+                    return Ok(token);
+                }
+            }
+            self.position = start;
+        }
+        self.position = self.input.len();
+        Ok(Some(remaining.into()))
+    }
+
+    pub fn rest(&mut self, name: &str) -> Result<String, ArgError> {
+        match self.optional_rest()? {
+            Some(rest) => Ok(rest),
+            None => Err(self.error(format!{"Missing <{}>.", name}))
+        }
+    }
+
+    // Complains about anything nobody asked for
+    pub fn finish(&self) -> Result<(), ArgError> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        Err(self.error(format!{"Did not expect {}.", self.remaining().trim()}))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_split_on_spaces() {
+        let mut args = Args::parse("look   at  sign ");
+        assert_eq!(args.word("a").unwrap(), "at");
+        assert_eq!(args.word("b").unwrap(), "sign");
+        assert!(args.optional_word().unwrap().is_none());
+        assert!(args.finish().is_ok());
+    }
+
+    #[test]
+    fn quotes_keep_spaces_and_escapes() {
+        let mut args = Args::parse(r#"i "old \"rusty\" lever" "back\\slash" "" pull"#);
+        assert_eq!(args.word("object").unwrap(), r#"old "rusty" lever"#);
+        assert_eq!(args.word("object").unwrap(), r"back\slash");
+        assert_eq!(args.optional_word().unwrap(), Some(String::new()));
+        assert_eq!(args.word("action").unwrap(), "pull");
+    }
+
+    #[test]
+    fn unclosed_quotes_are_an_error() {
+        let mut args = Args::parse(r#"i "old lever"#);
+        assert!(args.word("object").unwrap_err().to_string().contains("closing \" quote"));
+        let mut args = Args::parse(r#"i "old lever\"#);
+        assert!(args.word("object").is_err());
+    }
+
+    #[test]
+    fn empty_quotes_are_not_a_word() {
+        let mut args = Args::parse(r#"i """#);
+        assert!(args.word("object").unwrap_err().to_string().contains("Missing <object>."));
+    }
+
+    #[test]
+    fn rest_is_raw_unless_it_is_one_quoted_string() {
+        let mut args = Args::parse(r#"say  hello "there"  you "#);
+        assert_eq!(args.rest("text").unwrap(), r#"hello "there"  you"#);
+        let mut args = Args::parse(r#"say "hello \"you\"" "#);
+        assert_eq!(args.rest("text").unwrap(), r#"hello "you""#);
+        let mut args = Args::parse(r#"say "hello" you"#);
+        assert_eq!(args.rest("text").unwrap(), r#""hello" you"#);
+        let mut args = Args::parse(r#"say "unclosed"#);
+        assert_eq!(args.rest("text").unwrap(), r#""unclosed"#);
+    }
+
+    #[test]
+    fn optional_rest_and_rest_when_empty() {
+        let mut args = Args::parse("say   ");
+        assert!(args.optional_rest().unwrap().is_none());
+        assert!(args.rest("text").unwrap_err().to_string().contains("Missing <text>."));
+    }
+
+    #[test]
+    fn rest_takes_everything_left() {
+        let mut args = Args::parse("whisper bob meet me at the well");
+        assert_eq!(args.word("player").unwrap(), "bob");
+        assert_eq!(args.rest("message").unwrap(), "meet me at the well");
+        assert!(args.finish().is_ok());
+    }
+
+    #[test]
+    fn typed_arguments() {
+        let mut args = Args::parse("x 42 -7 many cave_1 bad/room");
+        assert_eq!(args.integer("count").unwrap(), 42);
+        assert_eq!(args.integer("count").unwrap(), -7);
+        assert!(args.integer("count").unwrap_err().to_string().contains("<count> should be a whole number, not many."));
+        assert_eq!(args.room_addr("room").unwrap(), "cave_1");
+        assert!(args.room_addr("room").unwrap_err().to_string().contains("bad/room is not a valid <room>"));
+        assert!(args.integer("count").unwrap_err().to_string().contains("Missing <count>."));
+    }
+
+    #[test]
+    fn identifiers_cannot_be_paths() {
+        let mut args = Args::parse("x bob ../players/bob ./bob \"bo b\" abcdefghijklmnopqrstu");
+        assert_eq!(args.identifier("player").unwrap(), "bob");
+        for bad in ["../players/bob", "./bob", "bo b", "abcdefghijklmnopqrstu"] {
+            assert!(args.identifier("player").unwrap_err().to_string().contains(&format!{"{} is not a valid <player>", bad}));
+        }
+        assert!(args.check_identifier("dyon/../x", "script").is_err());
+        assert!(args.check_identifier("flower", "script").is_ok());
+    }
+
+    #[test]
+    fn finish_complains_about_extras() {
+        let mut args = Args::parse("look sign  extra words ");
+        args.word("object").unwrap();
+        assert!(args.finish().unwrap_err().to_string().contains("Did not expect extra words."));
+    }
+
+    #[test]
+    fn errors_carry_the_usage_line() {
+        let usage = COMMANDS.find("look").unwrap().usage;
+        let args = Args::parse("look a b");
+        assert_eq!(args.error("Nope.".into()).to_string(), format!{"@BNope.\nUsage: {}", usage});
+        let args = Args::parse("notacommand a");
+        assert_eq!(args.error("Nope.".into()).to_string(), "@BNope.");
+    }
+}
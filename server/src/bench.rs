@@ -0,0 +1,65 @@
+use std::{sync::Arc, time::Instant};
+
+use crate::{config::ServerConfig, dyon_inter, states::{ClientState, ServerState}, storage::MemoryStore};
+
+// Busy enough that running it dwarfs handing it to a worker
+const SCRIPT: &str = "fn home(state) -> str {
+  sum := 0
+  for i 20000 {
+    sum += i
+  }
+  return \"done\"
+}
+";
+
+// Runs count RunScript actions at once, returns how many finished
+async fn run_all(server_state: &Arc<ServerState>, path: &str, count: usize) -> usize {
+    let mut runs = vec![];
+    for n in 0..count {
+        let server_state = server_state.clone();
+        let path = path.to_string();
+        runs.push(tokio::spawn(async move {
+            let client = ClientState::new(None).into_pointer();
+            dyon_inter::load_and_run(&path, client, &server_state, &format!{"benchmark player {}", n}).await.is_ok()
+        }));
+    }
+    let mut finished = 0;
+    for run in runs {
+        if run.await.unwrap_or(false) {
+            finished += 1;
+        }
+    }
+    finished
+}
+
+// How many of actions simultaneous RunScript actions finish per second with workers script workers
+async fn measure(path: &str, workers: usize, actions: usize) -> std::io::Result<()> {
+    let config = ServerConfig { script_workers: workers, ..Default::default() };
+    let server_state = Arc::new(ServerState::new(config, Box::new(MemoryStore::default()))?);
+    let workers = server_state.sandbox.workers();
+    // One run per worker up front starts every worker process and has it parse the script,
+    // so only running is measured
+    if run_all(&server_state, path, workers).await < workers {
+        return Err(std::io::Error::other("the benchmark script did not run"));
+    }
+
+    let start = Instant::now();
+    let finished = run_all(&server_state, path, actions).await;
+    let elapsed = start.elapsed();
+    println!("{} workers: {}/{} actions in {}ms, {:.0} actions/sec",
+        workers, finished, actions, elapsed.as_millis(), finished as f64 / elapsed.as_secs_f64());
+    Ok(())
+}
+
+// server bench-scripts [actions] [workers], compares one script worker against a pool, 0 workers is one per cpu
+pub async fn run(actions: usize, workers: usize) -> std::io::Result<()> {
+    let path = std::env::temp_dir().join(format!{"bench-{}.dyon", std::process::id()});
+    std::fs::write(&path, SCRIPT)?;
+    let path = path.to_string_lossy().to_string();
+    let result = async {
+        measure(&path, 1, actions).await?;
+        measure(&path, workers, actions).await
+    }.await;
+    let _ = std::fs::remove_file(&path);
+    result
+}
@@ -0,0 +1,92 @@
+use std::{collections::{HashMap, VecDeque}, sync::Mutex};
+
+use crate::{config::ChannelConfig, states::Role};
+
+struct Channel {
+    min_role: Role,
+    history_len: usize,
+    history: VecDeque<String>
+}
+
+// The server wide channels from config.json, who is on them lives in each ClientState
+pub struct Channels {
+    // Never held across an await
+    channels: Mutex<HashMap<String, Channel>>,
+    // Config order, for listing
+    names: Vec<String>,
+    auto_join: Vec<String>
+}
+
+impl Channels {
+    pub fn new(configs: &[ChannelConfig]) -> Self {
+        let channels = configs.iter()
+            .map(|a| (a.name.clone(), Channel { min_role: a.min_role, history_len: a.history, history: VecDeque::new() }))
+            .collect();
+        Self {
+            channels: Mutex::new(channels),
+            names: configs.iter().map(|a| a.name.clone()).collect(),
+            auto_join: configs.iter().filter(|a| a.auto_join).map(|a| a.name.clone()).collect()
+        }
+    }
+
+    // None if there is no such channel
+    pub fn min_role(&self, name: &str) -> Option<Role> {
+        self.channels.lock().unwrap().get(name).map(|a| a.min_role)
+    }
+
+    pub fn visible_to(&self, role: Role) -> Vec<String> {
+        self.names.iter().filter(|a| self.min_role(a).map(|b| b <= role).unwrap_or(false)).cloned().collect()
+    }
+
+    pub fn auto_join(&self) -> Vec<String> {
+        self.auto_join.clone()
+    }
+
+    pub fn record(&self, name: &str, line: String) {
+        if let Some(channel) = self.channels.lock().unwrap().get_mut(name) {
+            if channel.history_len == 0 {
+                return;
+            }
+            if channel.history.len() >= channel.history_len {
+                channel.history.pop_front();
+            }
+            channel.history.push_back(line);
+        }
+    }
+
+    // Oldest first
+    pub fn history(&self, name: &str) -> Vec<String> {
+        self.channels.lock().unwrap().get(name).map(|a| a.history.iter().cloned().collect()).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(name: &str, min_role: Role, history: usize) -> ChannelConfig {
+        ChannelConfig { name: name.into(), min_role, history, ..Default::default() }
+    }
+
+    #[test]
+    fn history_keeps_the_newest_lines() {
+        let channels = Channels::new(&[channel("ooc", Role::Player, 2), channel("quiet", Role::Player, 0)]);
+        for line in ["one", "two", "three"] {
+            channels.record("ooc", line.into());
+            channels.record("quiet", line.into());
+        }
+        assert_eq!(channels.history("ooc"), vec!["two", "three"]);
+        assert!(channels.history("quiet").is_empty());
+        assert!(channels.history("nope").is_empty());
+    }
+
+    #[test]
+    fn channels_are_only_visible_from_their_min_role() {
+        let channels = Channels::new(&[channel("ooc", Role::Player, 20), channel("builders", Role::Builder, 20)]);
+        assert_eq!(channels.min_role("builders"), Some(Role::Builder));
+        assert_eq!(channels.min_role("nope"), None);
+        assert_eq!(channels.visible_to(Role::Player), vec!["ooc"]);
+        assert_eq!(channels.visible_to(Role::Builder), vec!["ooc", "builders"]);
+        assert_eq!(channels.visible_to(Role::Admin), vec!["ooc", "builders"]);
+    }
+}
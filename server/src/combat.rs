@@ -0,0 +1,154 @@
+use std::{sync::Arc, time::{Duration, Instant}};
+
+use crate::states::{ClientPointer, ClientState, ServerState};
+
+pub const COMBAT_SKILL: &str = "combat";
+pub const STARTING_HEALTH: i64 = 100;
+pub const STARTING_STAMINA: i64 = 50;
+
+// Weapons roll their power from an item template
+pub fn weapon_power(client: &ClientState) -> i64 {
+    client.equipped.as_ref().and_then(|a| a.stats.get("power")).copied().unwrap_or(0)
+}
+
+// rng(1, 4) + combat level + a fifth of the weapon's power
+pub fn damage(server_state: &ServerState, client: &ClientState) -> i64 {
+    server_state.roll(1, 4) + client.skill_level(COMBAT_SKILL) + weapon_power(client) / 5
+}
+
+pub fn weapon_name(client: &ClientState) -> String {
+    client.equipped.as_ref().map(|a| a.name.clone()).unwrap_or_else(|| "bare hands".into())
+}
+
+// Err says why they cannot swing yet, otherwise spends the stamina
+pub fn ready(server_state: &ServerState, client: &mut ClientState) -> Result<(), String> {
+    let cooldown = Duration::from_millis(server_state.config.attack_cooldown_ms);
+    if let Some(last) = client.last_attack {
+        if last.elapsed() < cooldown {
+            return Err("You are still recovering from your last swing.".into());
+        }
+    }
+    if client.stamina < server_state.config.attack_stamina {
+        return Err("You are too tired to fight, rest a moment.".into());
+    }
+    client.stamina -= server_state.config.attack_stamina;
+    client.last_attack = Some(Instant::now());
+    Ok(())
+}
+
+// One swing at another player, everyone in the room sees how it went
+pub async fn attack_player(server_state: &Arc<ServerState>, attacker: &ClientPointer, target: &ClientPointer) -> String {
+    let (name, room, damage, weapon) = {
+        let mut attacker_ref = attacker.lock().await;
+        if let Err(e) = ready(server_state, &mut attacker_ref) {
+            return e;
+        }
+        let damage = damage(server_state, &attacker_ref);
+        (attacker_ref.name.clone(), attacker_ref.current_room.clone(), damage, weapon_name(&attacker_ref))
+    };
+
+    let (target_name, health, max_health) = {
+        let mut target_ref = target.lock().await;
+        target_ref.health -= damage;
+        (target_ref.name.clone(), target_ref.health.max(0), target_ref.max_health)
+    };
+    let hit = format!{"@B{} hits {} with {} for {} ({}/{}).", name, target_name, weapon, damage, health, max_health};
+    server_state.send_to_room(&room, hit.clone(), Some(attacker)).await;
+    if health > 0 {
+        return hit;
+    }
+
+    // The reply would land after the death notices, so push the killing blow ahead of them
+    attacker.lock().await.send(hit);
+    server_state.send_to_room(&room, format!{"@B{} has been slain by {}!", target_name, name}, Some(attacker)).await;
+    respawn(server_state, target).await;
+    format!{"You have slain {}!", target_name}
+}
+
+// Back on their feet in the respawn room with full health and stamina
+pub async fn respawn(server_state: &Arc<ServerState>, client: &ClientPointer) {
+    {
+        let mut client_ref = client.lock().await;
+        client_ref.health = client_ref.max_health;
+        client_ref.stamina = client_ref.max_stamina;
+        client_ref.send("@BYou died. You wake up somewhere familiar.".into());
+    }
+    let respawn_room = server_state.config.respawn_room.clone();
+    server_state.teleport(client, &respawn_room).await;
+}
+
+// Topped up a little every regen_secs
+pub async fn regenerate(server_state: &Arc<ServerState>) {
+    let clients: Vec<ClientPointer> = server_state.client_states.lock().await.clone();
+    for client in clients {
+        let mut client_ref = client.lock().await;
+        client_ref.health = (client_ref.health + server_state.config.health_regen).min(client_ref.max_health);
+        client_ref.stamina = (client_ref.stamina + server_state.config.stamina_regen).min(client_ref.max_stamina);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::ServerConfig, storage::MemoryStore};
+    use tokio::sync::mpsc;
+
+    async fn arena() -> Arc<ServerState> {
+        let config = ServerConfig { respawn_room: "temple".into(), attack_stamina: 5, script_workers: 1, ..Default::default() };
+        let server_state = Arc::new(ServerState::new(config, Box::new(MemoryStore::default())).unwrap());
+        server_state.new_room(&"arena".into()).await;
+        server_state.new_room(&"temple".into()).await;
+        server_state
+    }
+
+    fn fighter(name: &str, health: i64) -> ClientState {
+        let mut client = ClientState::new(None);
+        client.name = name.into();
+        client.current_room = "arena".into();
+        client.health = health;
+        client
+    }
+
+    #[tokio::test]
+    async fn a_killing_blow_respawns_the_target() {
+        let server_state = arena().await;
+        let attacker = fighter("amy", STARTING_HEALTH).into_pointer();
+        let (outbox, mut inbox) = mpsc::unbounded_channel();
+        let mut target = fighter("bob", 1);
+        target.stamina = 0;
+        target.outbox = Some(outbox);
+        let target = target.into_pointer();
+
+        assert_eq!(attack_player(&server_state, &attacker, &target).await, "You have slain bob!");
+        let target_ref = target.lock().await;
+        assert_eq!(target_ref.health, target_ref.max_health);
+        assert_eq!(target_ref.stamina, target_ref.max_stamina);
+        assert_eq!(target_ref.current_room, "temple");
+        assert_eq!(inbox.try_recv().unwrap(), "@BYou died. You wake up somewhere familiar.");
+        assert_eq!(attacker.lock().await.stamina, STARTING_STAMINA - 5);
+    }
+
+    #[tokio::test]
+    async fn survivors_stay_where_they_are() {
+        let server_state = arena().await;
+        let attacker = fighter("amy", STARTING_HEALTH).into_pointer();
+        let target = fighter("bob", STARTING_HEALTH).into_pointer();
+
+        let reply = attack_player(&server_state, &attacker, &target).await;
+        assert!(reply.starts_with("@Bamy hits bob with bare hands for "), "{}", reply);
+        let target_ref = target.lock().await;
+        assert!(target_ref.health < STARTING_HEALTH);
+        assert_eq!(target_ref.current_room, "arena");
+    }
+
+    #[tokio::test]
+    async fn swings_wait_for_the_cooldown_and_stamina() {
+        let server_state = arena().await;
+        let mut client = fighter("amy", STARTING_HEALTH);
+        assert_eq!(ready(&server_state, &mut client), Ok(()));
+        assert_eq!(ready(&server_state, &mut client), Err("You are still recovering from your last swing.".into()));
+        client.last_attack = None;
+        client.stamina = 4;
+        assert_eq!(ready(&server_state, &mut client), Err("You are too tired to fight, rest a moment.".into()));
+    }
+}
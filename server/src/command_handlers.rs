@@ -1,250 +1,1259 @@
-use std::{sync::Arc, fs::OpenOptions, io::{Write, BufWriter}};
+use std::{sync::Arc, fs::OpenOptions, io::{Write, BufWriter}, time::Instant};
 
-use regex::Regex;
+use crate::{accounts, args::Args, combat, offline, items::{self, ItemTemplate}, npcs::{self, Loot, Npc, SpawnRule}, skills::{self, SkillRequirement}, sandbox::ScriptError, states::{ServerState, ClientPointer, ClientState, GameObject, GameAction, Role, unix_now}, timers::TimerAction};
 
-use crate::{lazy_static, states::{ServerState, ClientPointer, GameObject, GameAction}};
+pub async fn login(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let username = arg!(args.identifier("username"));
+    let password = arg!(args.rest("password"));
 
-pub async fn login(input: &String, server_state: Arc<ServerState>, client: ClientPointer) -> String {
-    lazy_static! {
-        static ref LOGIN_REGEX: Regex = Regex::new(r#"\\login ([^ ]+)"#).unwrap();
+    if server_state.is_playing(&username) {
+        return "That character is already playing.".into();
     }
 
-    if !LOGIN_REGEX.is_match(&input) {
-        return String::from("\\login <username>");
-    }
-    let captures = LOGIN_REGEX.captures(&input).unwrap();
-    let username = String::from(captures.get(1).unwrap().as_str());
-
-    let new_client = server_state.load_client(username).await;
+    let account = match server_state.load_account(&username).await {
+        Ok(account) => account,
+        Err(e) => {
+            eprintln!("Could not load the account {}: {}", username, e);
+            return "That character could not be loaded, ask an admin.".into();
+        }
+    };
 
-    if let Some(new_client) = new_client {
+    if let Some(account) = account {
+        if account.password_hash.is_empty() {
+            return "That character has no password, ask an admin to \\reset_password it.".into();
+        }
+        if let Err(e) = server_state.login_throttle.check(&account.name, &account.password_hash, password).await {
+            return e;
+        }
+        // The account's own name, not what they typed, is what gets claimed
+        let username = account.name;
+        if !server_state.claim_name(&username) {
+            return "That character is already playing.".into();
+        }
+        // Read again now it is ours, whoever had it last may have saved since
+        let mut new_client = match server_state.load_client(username.clone()).await {
+            Ok(Some(new_client)) => new_client,
+            Ok(None) => {
+                server_state.release_name(&username);
+                return "That is not a valid user".into();
+            },
+            Err(e) => {
+                server_state.release_name(&username);
+                eprintln!("Could not load the account {}: {}", username, e);
+                return "That character could not be loaded, ask an admin.".into();
+            }
+        };
+        // Keep the old character's progress before swapping it out
+        client.lock().await.logged_out();
+        if let Err(e) = server_state.save_client_if_dirty(client.clone()).await {
+            server_state.release_name(&username);
+            return format!{"Could not save your current character: {}", e};
+        }
+        let old_name = client.lock().await.name.clone();
+        server_state.release_name(&old_name);
+        server_state.depart(&client).await;
+        new_client.last_seen = unix_now();
+        // Before taking the lock, it may run their offline script
+        let report = offline::apply(&server_state, &mut new_client).await;
         let mut client_ref = client.lock().await;
-        let addr_from = client_ref.addr;
+        new_client.addr = client_ref.addr;
+        new_client.outbox = client_ref.outbox.take();
         *client_ref = new_client;
-        return format!{"Logged in!"};
+        drop(client_ref);
+        server_state.arrive(&client).await;
+        if let Some(report) = report {
+            return format!{"Logged in!\n{}", report};
+        }
+        return "Logged in!".into();
+    }
+
+    "That is not a valid user".into()
+}
+
+pub async fn change_password(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let old_password = arg!(args.word("old password"));
+    let new_password = arg!(args.rest("new password"));
+
+    if new_password.len() < 4 {
+        return "Passwords need at least 4 characters.".into();
+    }
+
+    // Check without holding the lock, a wrong password stalls for a while
+    let (name, hash) = {
+        let client_ref = client.lock().await;
+        (client_ref.name.clone(), client_ref.password_hash.clone())
+    };
+    if let Err(e) = server_state.login_throttle.check(&name, &hash, old_password).await {
+        return e;
     }
 
-    format!{"That is not a valid user"}
+    let new_hash = accounts::hash_password(new_password).await;
+    if let Some(new_hash) = new_hash {
+        client.lock().await.password_hash = new_hash;
+        if let Err(e) = server_state.save_client(client).await {
+            return format!{"Password changed but saving failed: {}", e};
+        }
+        return "Password changed.".into();
+    }
+    "Could not change your password.".into()
 }
 
-pub async fn add_link(input: &String, server_state: Arc<ServerState>, client: ClientPointer) -> String {
-    lazy_static! {
-        static ref ADD_LINK_REGEX: Regex = Regex::new(r#"\\link ([^ ]+)"#).unwrap();
+pub async fn grant(input: &str, server_state: Arc<ServerState>, _client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let username = arg!(args.identifier("username"));
+    let role_name = arg!(args.word("role"));
+    arg!(args.finish());
+    let role = Role::parse(&role_name);
+    if role.is_none() {
+        return args.error(format!{"{} is not a role, pick player, builder or admin.", role_name}).to_string();
+    }
+    let role = role.unwrap();
+    let username = username.as_str();
+
+    // Online players get it straight away
+    let online: Vec<ClientPointer> = server_state.client_states.lock().await.clone();
+    for other in online {
+        let mut other_ref = other.lock().await;
+        if other_ref.name == username {
+            other_ref.role = role;
+            drop(other_ref);
+            if let Err(e) = server_state.save_client(other).await {
+                return format!{"Granted but saving failed: {}", e};
+            }
+            server_state.send_to_player(username, format!{"@DYou are now {}.", role}).await;
+            return format!{"{} is now {}.", username, role};
+        }
+    }
+
+    // Straight from the store, load_client is for logging in. Held so they cannot log in
+    // halfway through and have their save undo it, same as reset_password.
+    if !server_state.claim_name(username) {
+        return format!{"{} just logged in, try again.", username};
     }
+    let result = match server_state.load_account(username).await {
+        Ok(Some(mut account)) => {
+            account.role = role;
+            match server_state.with_store(move |store| store.save_account(&account)).await {
+                Ok(()) => format!{"{} is now {}.", username, role},
+                Err(e) => format!{"Saving failed: {}", e}
+            }
+        },
+        Ok(None) => "That is not a valid user".into(),
+        Err(e) => format!{"Could not read {}: {}", username, e}
+    };
+    server_state.release_name(username);
+    result
+}
 
-    // TODO make this a macro
-    if !ADD_LINK_REGEX.is_match(&input) {
-        return String::from("\\link <to place>");
+pub async fn reset_password(input: &str, server_state: Arc<ServerState>, _client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let username = arg!(args.identifier("username"));
+    let password = arg!(args.rest("new password"));
+    if password.len() < 4 {
+        return "Passwords need at least 4 characters.".into();
     }
+    let hash = accounts::hash_password(password).await;
+    if hash.is_none() {
+        return "Could not hash that password.".into();
+    }
+    let hash = hash.unwrap();
+
+    if let Some(other) = server_state.find_player(&username).await {
+        other.lock().await.password_hash = hash;
+        if let Err(e) = server_state.save_client(other).await {
+            return format!{"Password reset but saving failed: {}", e};
+        }
+        return format!{"{}'s password was reset.", username};
+    }
+
+    // Held so they cannot log in halfway through and have their save undo it
+    if !server_state.claim_name(&username) {
+        return format!{"{} just logged in, try again.", username};
+    }
+    let result = match server_state.load_account(&username).await {
+        Ok(Some(mut account)) => {
+            account.password_hash = hash;
+            match server_state.with_store(move |store| store.save_account(&account)).await {
+                Ok(()) => format!{"{}'s password was reset.", username},
+                Err(e) => format!{"Saving failed: {}", e}
+            }
+        },
+        Ok(None) => "That is not a valid user".into(),
+        Err(e) => format!{"Could not read {}: {}", username, e}
+    };
+    server_state.release_name(&username);
+    result
+}
 
-    let captures = ADD_LINK_REGEX.captures(&input).unwrap();
-    let object_name = String::from(captures.get(1).unwrap().as_str());
-    let room = server_state.get_room(&client.lock().await.current_room);
+pub async fn add_link(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let object_name = arg!(args.room_addr("to place"));
+    arg!(args.finish());
+    let room = server_state.get_room(&client.lock().await.current_room).await;
     if let Some(room) = room {
         let mut room = room.lock().await;
-        if server_state.get_room(&object_name).is_none() {
-            server_state.new_room(&object_name);
+        if server_state.get_room(&object_name).await.is_none() {
+            server_state.new_room(&object_name).await;
         }
-        room.links.push(object_name.into());
-        return format!{"Added"};
+        if !room.links.contains(&object_name) {
+            room.links.push(object_name);
+        }
+        return "Added".into();
     }
-    return format!{"Not in a room?"};
+    "Not in a room?".into()
 }
 
-pub async fn move_into(input: &String, server_state: Arc<ServerState>, client: ClientPointer) -> String {
-    lazy_static! {
-        // touch <name> <action>
-        static ref MOVE_REGEX: Regex = Regex::new("move ([^ ]+)").unwrap();
-    }
+pub async fn move_into(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let link_name = arg!(args.room_addr("area"));
+    arg!(args.finish());
 
-    let room = server_state.get_room(&client.lock().await.current_room);
+    let (name, from, hidden) = {
+        let client_ref = client.lock().await;
+        (client_ref.name.clone(), client_ref.current_room.clone(), server_state.is_hidden(&client_ref))
+    };
+    let room = server_state.get_room(&from).await;
     if room.is_none() {
         return String::from("You belong to an invalid room.");
     }
-    let room_un = room.unwrap();
-    let room_ref = room_un.lock().await;
-    if !MOVE_REGEX.is_match(input) {
-        return format!{"move <link name>"};
-    }
-    let captures = MOVE_REGEX.captures(&input).unwrap();
-    let link_name = captures.get(1).unwrap().as_str();
-    if room_ref.links.contains(&link_name.into()) {
-        client.lock().await.current_room = link_name.into();
-        return format!{"You move into {}", link_name};
-    } else {
+    if !room.unwrap().lock().await.links.contains(&link_name) {
         return "That area does not exist here.".into();
     }
+
+    server_state.leave_room(&client, &from).await;
+    if !hidden {
+        server_state.send_to_room(&from, format!{"@C{} leaves toward {}.", name, link_name}, None).await;
+    }
+    client.lock().await.current_room = link_name.clone();
+    if !hidden {
+        server_state.send_to_room(&link_name, format!{"@C{} arrives from {}.", name, from}, None).await;
+    }
+    server_state.enter_room(&client, &link_name).await;
+    format!{"You move into {}", link_name}
+}
+
+pub async fn say(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let message = arg!(args.rest("message"));
+    let (name, room) = {
+        let client_ref = client.lock().await;
+        (client_ref.name.clone(), client_ref.current_room.clone())
+    };
+    server_state.send_to_room(&room, format!{"@C{} says, \"{}\"", name, message}, Some(&client)).await;
+    format!{"You say, \"{}\"", message}
+}
+
+pub async fn emote(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let action = arg!(args.rest("action"));
+    let (name, room) = {
+        let client_ref = client.lock().await;
+        (client_ref.name.clone(), client_ref.current_room.clone())
+    };
+    let emote = format!{"{} {}", name, action};
+    server_state.send_to_room(&room, format!{"@C{}", emote}, Some(&client)).await;
+    emote
+}
+
+// Shared by whisper and reply, whispers reach anyone online wherever they are
+// unless they are hidden from the sender, who is told the same as if they were offline
+async fn send_whisper(server_state: &ServerState, client: &ClientPointer, target: &str, message: &str) -> String {
+    let (name, role) = {
+        let client_ref = client.lock().await;
+        (client_ref.name.clone(), client_ref.role)
+    };
+    if name == target {
+        return "You mutter to yourself.".into();
+    }
+    let other = match server_state.find_player(target).await {
+        Some(other) => other,
+        None => return format!{"{} is not online.", target}
+    };
+    let mut other_ref = other.lock().await;
+    if !server_state.can_see(role, &other_ref) {
+        return format!{"{} is not online.", target};
+    }
+    other_ref.last_whisper_from = Some(name.clone());
+    other_ref.send(format!{"@C{} whispers, \"{}\"", name, message});
+    format!{"You whisper to {}, \"{}\"", target, message}
+}
+
+pub async fn whisper(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let target = arg!(args.word("player"));
+    let message = arg!(args.rest("message"));
+    send_whisper(&server_state, &client, &target, &message).await
+}
+
+pub async fn reply(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let message = arg!(args.rest("message"));
+    let target = client.lock().await.last_whisper_from.clone();
+    match target {
+        Some(target) => send_whisper(&server_state, &client, &target, &message).await,
+        None => "Nobody has whispered to you yet.".into()
+    }
+}
+
+// Checks the channel exists and their role lets them on it
+async fn usable_channel(server_state: &ServerState, client: &ClientPointer, channel: &str) -> Result<(), String> {
+    let role = client.lock().await.role;
+    match server_state.channels.min_role(channel) {
+        None => Err(format!{"There is no {} channel, type channels for a list.", channel}),
+        Some(min_role) if role < min_role => {
+            let article = if min_role == Role::Admin { "an" } else { "a" };
+            Err(format!{"@BYou need to be {} {} to use {}.", article, min_role, channel})
+        },
+        _ => Ok(())
+    }
+}
+
+pub async fn list_channels(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let args = Args::parse(input);
+    arg!(args.finish());
+    let client_ref = client.lock().await;
+    let mut lines = vec![String::from("@DChannels:")];
+    for channel in server_state.channels.visible_to(client_ref.role) {
+        let joined = client_ref.channels.contains(&channel);
+        let muted = client_ref.muted_channels.contains(&channel);
+        lines.push(match (joined, muted) {
+            (true, true) => format!{"{} (joined, muted)", channel},
+            (true, false) => format!{"{} (joined)", channel},
+            _ => channel
+        });
+    }
+    lines.push("Type join <channel> to subscribe, then <channel> <message> to talk on it.".into());
+    lines.join("\n")
+}
+
+pub async fn join_channel(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let channel = arg!(args.word("channel"));
+    arg!(args.finish());
+    if let Err(e) = usable_channel(&server_state, &client, &channel).await {
+        return e;
+    }
+    let mut client_ref = client.lock().await;
+    if client_ref.channels.contains(&channel) {
+        return format!{"You are already on {}.", channel};
+    }
+    client_ref.channels.push(channel.clone());
+
+    let history = server_state.channels.history(&channel);
+    if history.is_empty() {
+        return format!{"You join {}.", channel};
+    }
+    format!{"You join {}. Recently:\n{}", channel, history.join("\n")}
+}
+
+pub async fn leave_channel(input: &str, _server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let channel = arg!(args.word("channel"));
+    arg!(args.finish());
+    let mut client_ref = client.lock().await;
+    if !client_ref.channels.contains(&channel) {
+        return format!{"You are not on {}.", channel};
+    }
+    client_ref.channels.retain(|a| *a != channel);
+    client_ref.muted_channels.retain(|a| *a != channel);
+    format!{"You leave {}.", channel}
+}
+
+// Handles both mute and unmute, muted channels stay joined but stay quiet
+pub async fn mute_channel(input: &str, _server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let channel = arg!(args.word("channel"));
+    arg!(args.finish());
+    let mute = input.starts_with("mute");
+    let mut client_ref = client.lock().await;
+    if !client_ref.channels.contains(&channel) {
+        return format!{"You are not on {}.", channel};
+    }
+    let muted = client_ref.muted_channels.contains(&channel);
+    if mute == muted {
+        return format!{"{} is already {}.", channel, if muted { "muted" } else { "unmuted" }};
+    }
+    if mute {
+        client_ref.muted_channels.push(channel.clone());
+        return format!{"You mute {}.", channel};
+    }
+    client_ref.muted_channels.retain(|a| *a != channel);
+    format!{"You unmute {}.", channel}
+}
+
+pub async fn chat(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let channel = arg!(args.word("channel"));
+    let message = arg!(args.rest("message"));
+    if let Err(e) = usable_channel(&server_state, &client, &channel).await {
+        return e;
+    }
+    let name = {
+        let client_ref = client.lock().await;
+        if !client_ref.channels.contains(&channel) {
+            return format!{"Join {} first.", channel};
+        }
+        if client_ref.muted_channels.contains(&channel) {
+            return format!{"You have {} muted, unmute it to talk there.", channel};
+        }
+        client_ref.name.clone()
+    };
+    let line = format!{"@D[{}] {}: {}", channel, name, message};
+    server_state.send_to_channel(&channel, line.clone(), Some(&client)).await;
+    line
+}
+
+// 42s, 5m, 3h, 2d
+fn short_duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!{"{}s", secs},
+        60..=3599 => format!{"{}m", secs / 60},
+        3600..=86399 => format!{"{}h", secs / 3600},
+        _ => format!{"{}d", secs / 86400}
+    }
+}
+
+fn role_badge(role: Role) -> &'static str {
+    match role {
+        Role::Player => "",
+        Role::Builder => "[B] ",
+        Role::Admin => "[A] "
+    }
+}
+
+pub async fn who(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let args = Args::parse(input);
+    arg!(args.finish());
+    let role = client.lock().await.role;
+    let online: Vec<ClientPointer> = server_state.client_states.lock().await.clone();
+    let mut lines = vec![];
+    for other in online {
+        let other_ref = other.lock().await;
+        // Still at the login prompt, or hiding from this viewer
+        if other_ref.name.is_empty() || !server_state.can_see(role, &other_ref) {
+            continue;
+        }
+        let mut line = format!{"{}{} - {}, idle {}", role_badge(other_ref.role), other_ref.name,
+            other_ref.current_room, short_duration(other_ref.last_input.elapsed().as_secs())};
+        if server_state.is_hidden(&other_ref) {
+            line = format!{"{} (invisible)", line};
+        }
+        lines.push(line);
+    }
+    lines.sort();
+    let count = lines.len();
+    lines.insert(0, "@DOnline:".into());
+    lines.push(format!{"{} online. [A] admin, [B] builder.", count});
+    lines.join("\n")
+}
+
+pub async fn finger(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let name = arg!(args.identifier("player"));
+    arg!(args.finish());
+    let role = client.lock().await.role;
+
+    if let Some(other) = server_state.find_player(&name).await {
+        let other_ref = other.lock().await;
+        if server_state.can_see(role, &other_ref) {
+            return format!{"@D{}{} is online in {}, idle {}.", role_badge(other_ref.role), other_ref.name,
+                other_ref.current_room, short_duration(other_ref.last_input.elapsed().as_secs())};
+        }
+    }
+
+    // Offline, or hidden and so treated as offline
+    let account: Option<ClientState> = match server_state.load_account(&name).await {
+        Ok(account) => account,
+        Err(e) => {
+            eprintln!("Could not load the account {}: {}", name, e);
+            return format!{"Could not read {}.", name};
+        }
+    };
+    match account {
+        Some(account) if account.last_seen == 0 => format!{"@D{}{} has not been seen in a long time.", role_badge(account.role), account.name},
+        Some(account) => format!{"@D{}{} was last seen {} ago in {}.", role_badge(account.role), account.name,
+            short_duration(unix_now().saturating_sub(account.last_seen)), account.current_room},
+        None => format!{"There is nobody called {}.", name}
+    }
+}
+
+pub async fn toggle_edit_mode(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let args = Args::parse(input);
+    arg!(args.finish());
+    let (name, room, editing) = {
+        let mut client_ref = client.lock().await;
+        client_ref.is_edit_mode = !client_ref.is_edit_mode;
+        (client_ref.name.clone(), client_ref.current_room.clone(), client_ref.is_edit_mode)
+    };
+    if !server_state.config.invisible_editors {
+        return format!{"Edit mode {}.", if editing { "on" } else { "off" }};
+    }
+    // To everyone else in the room it looks like they came or went
+    if editing {
+        server_state.send_to_room(&room, format!{"@C{} disappears.", name}, Some(&client)).await;
+        return "Edit mode on, players cannot see you.".into();
+    }
+    server_state.send_to_room(&room, format!{"@C{} appears.", name}, Some(&client)).await;
+    "Edit mode off, you are visible again.".into()
 }
 
 //Utility fn for upload_script
-fn save_script(file: &str, script: String) {
+fn save_script(file: &str, script: String) -> std::io::Result<()> {
+    if let Some(dir) = std::path::Path::new(file).parent() {
+        std::fs::create_dir_all(dir)?;
+    }
     let mut options = OpenOptions::new();
-    options.create(true).write(true);
-    let file = options.open(file).unwrap();
+    options.create(true).write(true).truncate(true);
+    let file = options.open(file)?;
     // Some reason tobytes on script is not returning a len > 0...
-    BufWriter::new(file).write_all(&script.as_bytes()).expect("Failed to write to script file");
+    let mut writer = BufWriter::new(file);
+    writer.write_all(script.as_bytes())?;
+    writer.flush()
 }
 
-pub async fn upload_script(input: &String) -> String {
-    lazy_static! {
-        // Match all chara until the first :
-        static ref SCRIPT_REGEX: Regex = Regex::new(r#"\\script ([^:]+)"#).unwrap();
+pub async fn upload_script(input: &str, server_state: Arc<ServerState>, _client: ClientPointer) -> String {
+    // Script contents are not arguments, so take the raw text and split at the first :
+    let mut args = Args::parse(input);
+    let upload = arg!(args.rest("name"));
+    let colon_spot = upload.find(':');
+    if colon_spot.is_none() {
+        return args.error("Missing the : between the name and the contents.".into()).to_string();
     }
+    let colon_spot = colon_spot.unwrap();
+    let script_file_name = upload[..colon_spot].trim();
+    arg!(args.check_identifier(script_file_name, "name"));
 
-    if !SCRIPT_REGEX.is_match(&input) {
-        return format!{"{}", input}
+    let script = &upload[(colon_spot + 1)..];
+    if script.trim().is_empty() {
+        return args.error("Must include script contents".into()).to_string();
+    }
+    // Gotta undo the loop hole here since we are using read_line as our interpreting
+    let path = format!("dyon/{}.dyon", script_file_name);
+    let script = script.replace("#n", "\n");
+    // A script that does not compile would only fail later in front of players
+    match server_state.sandbox.validate(&path, &script).await {
+        Ok(()) => (),
+        Err(ScriptError::Failed(e)) => return format!{"Did not save {}.dyon, it does not compile:\n{}", script_file_name, e.trim()},
+        Err(ScriptError::Stopped(e)) => return format!{"Did not save {}.dyon, compiling it was stopped, {}.", script_file_name, e}
+    }
+    if let Err(e) = save_script(&path, script) {
+        eprintln!("Could not save {}: {}", path, e);
+        return format!{"Could not save {}.dyon: {}", script_file_name, e};
     }
 
-    let captures = SCRIPT_REGEX.captures(&input).unwrap();
-    let script_file_name = captures.get(1).unwrap().as_str();
+    format! { "Wrote script {}.dyon", script_file_name }
+}
 
-    let colon_spot = input.find(":").unwrap();
-    if colon_spot == input.len() { // Could be memory error if we tried to index past length of string
-        return "Must include script contents".into();
+pub async fn reload_script(input: &str, server_state: Arc<ServerState>, _client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let script = arg!(args.identifier("script"));
+    arg!(args.finish());
+    let path = format!{"dyon/{}.dyon", script};
+    // Workers parse a script again once its file changes, so touching it reloads it everywhere
+    let touched = std::fs::File::options().write(true).open(&path).and_then(|a| a.set_modified(std::time::SystemTime::now()));
+    if let Err(e) = touched {
+        return format!{"{}.dyon did not load: {}", script, e};
     }
+    let started = Instant::now();
+    match server_state.sandbox.load(&path).await {
+        Ok(_) => format!{"Reloaded {}.dyon in {}ms.", script, started.elapsed().as_millis()},
+        Err(e) => format!{"{}.dyon did not load: {}", script, e}
+    }
+}
 
-    let script = &input[(colon_spot + 1)..];
-    // Gotta undo the loop hole here since we are using read_line as our interpreting
-    save_script(&format!("dyon/{}.dyon", script_file_name), script.replace("#n", "\n"));
+pub async fn save(_input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    if let Err(e) = server_state.save().await {
+        return format!{"Failed to save the world: {}", e};
+    }
+    if let Err(e) = server_state.save_client(client).await {
+        return format!{"Failed to save you: {}", e};
+    }
+    "Nice save!".into()
+}
 
-    format! { "Wrote script {}.dyon", script_file_name }
+pub async fn add_object(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let object_name = arg!(args.rest("object name"));
+    let room = server_state.get_room(&client.lock().await.current_room).await;
+    if let Some(room) = room {
+        let mut room = room.lock().await;
+        room.objects.insert(object_name.clone(), GameObject::new(object_name));
+        return "Added".into();
+    }
+    "Not in a room?".into()
+}
+
+pub async fn describe_object(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let object_name = arg!(args.object_name("object name"));
+    let object_description = arg!(args.rest("description"));
+
+    let room = server_state.get_room(&client.lock().await.current_room).await;
+    if let Some(room) = room {
+        let mut room = room.lock().await;
+        let game_object_ref = room.objects.get_mut(&object_name);
+        if let Some(game_object_ref) = game_object_ref {
+            game_object_ref.display = object_description;
+            return "Done".into();
+        }
+        return "Not a valid object.".into();
+    }
+    "Not in a room?".into()
 }
 
-pub async fn add_object(input: &String, server_state: Arc<ServerState>, client: ClientPointer) -> String {
-    lazy_static! {
-        static ref ADD_OBJECT_REGEX: Regex = Regex::new("\\add (.+)").unwrap();
+pub async fn add_action(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let object_name = arg!(args.object_name("object name"));
+    let action_name = arg!(args.word("action name"));
+    let action_string = arg!(args.rest("action string"));
+    let action = GameAction::parse_from_string(action_string.clone());
+    if let GameAction::None = action {
+        return args.error(format!{"{} is not an action string, try PrintText <text>, RunScript <script> or SpawnItem <template>.", action_string}).to_string();
+    }
+    if let GameAction::RunScript(script) = &action {
+        arg!(args.check_identifier(script, "script"));
     }
 
-    // TODO make this a macro
-    if !ADD_OBJECT_REGEX.is_match(&input) {
-        return String::from("\\add <object name>");
+    let room = server_state.get_room(&client.lock().await.current_room).await;
+    if let Some(room) = room {
+        let mut room = room.lock().await;
+        let game_object_ref = room.objects.get_mut(&object_name);
+        if let Some(game_object_ref) = game_object_ref {
+            game_object_ref.actions.insert(action_name, action);
+            return "Done".into();
+        }
+        return "Not a valid object.".into();
     }
+    "Not in a room?".into()
+}
 
-    let captures = ADD_OBJECT_REGEX.captures(&input).unwrap();
-    let object_name = captures.get(1).unwrap().as_str();
-    let room = server_state.get_room(&client.lock().await.current_room);
+pub async fn toggle_portable(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let object_name = arg!(args.object_name("object name"));
+    arg!(args.finish());
+
+    let room = server_state.get_room(&client.lock().await.current_room).await;
     if let Some(room) = room {
         let mut room = room.lock().await;
-        room.objects.insert(object_name.into(), GameObject::new(object_name.into()));
-        return format!{"Added"};
+        if let Some(game_object_ref) = room.objects.get_mut(&object_name) {
+            game_object_ref.portable = !game_object_ref.portable;
+            if game_object_ref.portable {
+                return format!{"{} can now be picked up.", object_name};
+            }
+            return format!{"{} is now fixed in place.", object_name};
+        }
+        return "Not a valid object.".into();
+    }
+    "Not in a room?".into()
+}
+
+pub async fn inventory(input: &str, _server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let args = Args::parse(input);
+    arg!(args.finish());
+    let client_ref = client.lock().await;
+    let mut resources: Vec<String> = client_ref.resources.iter().filter(|a| *a.1 != 0).map(|a| format!{"{} {}", a.1, a.0}).collect();
+    resources.sort();
+    let resources = if resources.is_empty() { String::new() } else { format!{"\n@CYou have {}.", resources.join(", ")} };
+    if client_ref.inventory.is_empty() {
+        return format!{"You are carrying nothing.{}", resources};
     }
-    return format!{"Not in a room?"};
+    let items = client_ref.inventory.iter().fold("@CYou are carrying:".into(), |a, b| format!{"{}\n{}", a, b.name});
+    format!{"{}{}", items, resources}
 }
 
-pub async fn describe_object(input: &String, server_state: Arc<ServerState>, client: ClientPointer) -> String {
-    lazy_static! {
-        // We have quotes here because object name may contain spaces
-        static ref DESCRIBE_OBJECT: Regex = Regex::new("\\describe \"(.+)\" (.+)").unwrap();
+pub async fn equip(input: &str, _server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let object_name = arg!(args.object_name("object"));
+    arg!(args.finish());
+    let mut client_ref = client.lock().await;
+    let object = match client_ref.take_carried(&object_name) {
+        Some(object) => object,
+        None => return format!{"You are not carrying a {}.", object_name}
+    };
+    let power = object.stats.get("power").copied().unwrap_or(0);
+    let previous = client_ref.equipped.replace(object);
+    match previous {
+        Some(previous) => {
+            let reply = format!{"You put away the {} and wield the {} (power {}).", previous.name, object_name, power};
+            client_ref.inventory.push(previous);
+            reply
+        },
+        None => format!{"You wield the {} (power {}).", object_name, power}
     }
+}
 
-    if !DESCRIBE_OBJECT.is_match(&input) {
-        return String::from("\\describe \"<object name>\" <description>");
+pub async fn unequip(input: &str, _server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let args = Args::parse(input);
+    arg!(args.finish());
+    let mut client_ref = client.lock().await;
+    match client_ref.equipped.take() {
+        Some(object) => {
+            let reply = format!{"You put away the {}.", object.name};
+            client_ref.inventory.push(object);
+            reply
+        },
+        None => "You are not holding anything.".into()
     }
+}
 
-    let captures = DESCRIBE_OBJECT.captures(&input).unwrap();
-    let object_name = captures.get(1).unwrap().as_str();
-    let object_description = captures.get(2).unwrap().as_str();
+pub async fn score(input: &str, _server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let args = Args::parse(input);
+    arg!(args.finish());
+    let client_ref = client.lock().await;
+    format!{"@C{}\nHealth {}/{}\nStamina {}/{}\nWielding {} (power {})\nCombat level {}",
+        client_ref.name, client_ref.health, client_ref.max_health, client_ref.stamina, client_ref.max_stamina,
+        combat::weapon_name(&client_ref), combat::weapon_power(&client_ref), client_ref.skill_level(combat::COMBAT_SKILL)}
+}
+
+pub async fn attack(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let target = arg!(args.word("target"));
+    arg!(args.finish());
 
-    let room = server_state.get_room(&client.lock().await.current_room);
+    let (name, current_room, role) = {
+        let client_ref = client.lock().await;
+        (client_ref.name.clone(), client_ref.current_room.clone(), client_ref.role)
+    };
+    if target == name {
+        return "You think better of it.".into();
+    }
+    let other = server_state.find_player(&target).await;
+    if let Some(other) = other {
+        let here = {
+            let other_ref = other.lock().await;
+            other_ref.current_room == current_room && server_state.can_see(role, &other_ref)
+        };
+        if here {
+            return combat::attack_player(&server_state, &client, &other).await;
+        }
+    }
+    if let Some(reply) = npcs::attack_npc(&server_state, &client, &current_room, &target).await {
+        return reply;
+    }
+    format!{"There is no {} here to attack.", target}
+}
+
+pub async fn set_idle_activity(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let skill = arg!(args.optional_word());
+    arg!(args.finish());
+    let mut client_ref = client.lock().await;
+    match skill.as_deref() {
+        None => match &client_ref.idle_activity {
+            Some(skill) => format!{"While you are away you practise {}.", skill},
+            None => "You do nothing while you are away. Type idle <skill> to practise one.".into()
+        },
+        Some("stop") => {
+            client_ref.idle_activity = None;
+            "You will rest while you are away.".into()
+        },
+        Some(skill) => {
+            if !client_ref.skills.contains_key(skill) {
+                return format!{"You can only practise skills you have learned, {} is not one of them.", skill};
+            }
+            client_ref.idle_activity = Some(skill.into());
+            format!{"While you are away you will practise {}, for up to {} hours.", skill, server_state.config.offline_max_hours}
+        }
+    }
+}
+
+pub async fn get_item(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let object_name = arg!(args.object_name("object"));
+    arg!(args.finish());
+
+    let (name, current_room) = {
+        let client_ref = client.lock().await;
+        (client_ref.name.clone(), client_ref.current_room.clone())
+    };
+    let room = server_state.get_room(&current_room).await;
+    if room.is_none() {
+        return String::from("You belong to an invalid room.");
+    }
+    let object = {
+        let room = room.unwrap();
+        let mut room_ref = room.lock().await;
+        match room_ref.objects.get(&object_name) {
+            None => return "The object does not exist".into(),
+            Some(object) if !object.portable => return format!{"The {} will not budge.", object_name},
+            _ => room_ref.objects.remove(&object_name).unwrap()
+        }
+    };
+    client.lock().await.inventory.push(object);
+    server_state.send_to_room(&current_room, format!{"@C{} picks up the {}.", name, object_name}, Some(&client)).await;
+    format!{"You pick up the {}.", object_name}
+}
+
+pub async fn drop_item(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let object_name = arg!(args.object_name("object"));
+    arg!(args.finish());
+
+    let (name, current_room) = {
+        let client_ref = client.lock().await;
+        (client_ref.name.clone(), client_ref.current_room.clone())
+    };
+    let room = server_state.get_room(&current_room).await;
+    if room.is_none() {
+        return String::from("You belong to an invalid room.");
+    }
+    {
+        let room = room.unwrap();
+        let mut room_ref = room.lock().await;
+        // Room objects are keyed by name so two of a kind cannot sit in one room
+        if room_ref.objects.contains_key(&object_name) {
+            return format!{"There is already a {} here.", object_name};
+        }
+        let object = client.lock().await.take_carried(&object_name);
+        match object {
+            Some(object) => room_ref.objects.insert(object_name.clone(), object),
+            None => return format!{"You are not carrying a {}.", object_name}
+        };
+    }
+    server_state.send_to_room(&current_room, format!{"@C{} drops the {}.", name, object_name}, Some(&client)).await;
+    format!{"You drop the {}.", object_name}
+}
+
+pub async fn give_item(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let target = arg!(args.word("player"));
+    let object_name = arg!(args.object_name("object"));
+    arg!(args.finish());
+
+    let (name, current_room, role) = {
+        let client_ref = client.lock().await;
+        (client_ref.name.clone(), client_ref.current_room.clone(), client_ref.role)
+    };
+    if target == name {
+        return "You already have it.".into();
+    }
+    let other = server_state.find_player(&target).await;
+    let other = match other {
+        Some(other) => other,
+        None => return format!{"{} is not here.", target}
+    };
+    {
+        let other_ref = other.lock().await;
+        if other_ref.current_room != current_room || !server_state.can_see(role, &other_ref) {
+            return format!{"{} is not here.", target};
+        }
+    }
+
+    let object = client.lock().await.take_carried(&object_name);
+    let object = match object {
+        Some(object) => object,
+        None => return format!{"You are not carrying a {}.", object_name}
+    };
+    let mut other_ref = other.lock().await;
+    other_ref.inventory.push(object);
+    other_ref.send(format!{"@C{} gives you the {}.", name, object_name});
+    format!{"You give the {} to {}.", object_name, target}
+}
+
+pub async fn set_requirement(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let object_name = arg!(args.object_name("object name"));
+    let action_name = arg!(args.word("action name"));
+    let skill = arg!(args.identifier("skill"));
+    let requirement = if skill == "none" {
+        None
+    } else {
+        let difficulty = arg!(args.integer("difficulty"));
+        let level = arg!(args.integer("level"));
+        Some(SkillRequirement { skill, difficulty, level })
+    };
+    arg!(args.finish());
+    if let Some(requirement) = &requirement {
+        if let Err(e) = skills::check_caps(requirement, &server_state.config) {
+            return args.error(e).to_string();
+        }
+    }
+
+    let room = server_state.get_room(&client.lock().await.current_room).await;
     if let Some(room) = room {
         let mut room = room.lock().await;
-        let game_object_ref = room.objects.get_mut(object_name.into());
+        let game_object_ref = room.objects.get_mut(&object_name);
         if let Some(game_object_ref) = game_object_ref {
-            game_object_ref.display = object_description.into();
-            return format!{"Done"};
+            if !game_object_ref.actions.contains_key(&action_name) {
+                return format!{"{} has no {} action.", object_name, action_name};
+            }
+            match requirement {
+                Some(requirement) => {
+                    let xp = requirement.difficulty * requirement.level;
+                    game_object_ref.requirements.insert(action_name, requirement);
+                    return format!{"Done, it teaches {} xp at most.", xp.min(server_state.config.max_action_xp)};
+                },
+                None => {
+                    game_object_ref.requirements.remove(&action_name);
+                    return "Done".into();
+                }
+            }
+        }
+        return "Not a valid object.".into();
+    }
+    "Not in a room?".into()
+}
+
+pub async fn list_skills(input: &str, _server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let args = Args::parse(input);
+    arg!(args.finish());
+    let client_ref = client.lock().await;
+    if client_ref.skills.is_empty() {
+        return "You have not learned any skills yet.".into();
+    }
+    let mut skill_names: Vec<&String> = client_ref.skills.keys().collect();
+    skill_names.sort();
+    let mut lines = vec![String::from("@CSkills:")];
+    for skill in skill_names {
+        let xp = client_ref.skills[skill];
+        let level = skills::level_for_xp(xp);
+        lines.push(format!{"{} - level {}, {}/{} xp", skill, level, xp, skills::xp_for_level(level + 1)});
+    }
+    lines.join("\n")
+}
+
+// Runs edit on the named spawn rule in the builder's room. Live copies are cleared
+// so the next npc tick spawns the changed version.
+async fn edit_spawn_rule<F>(server_state: &ServerState, client: &ClientPointer, npc_name: &str, edit: F) -> String
+where F: FnOnce(&mut SpawnRule) -> String {
+    let room = server_state.get_room(&client.lock().await.current_room).await;
+    if let Some(room) = room {
+        let mut room = room.lock().await;
+        let rule = room.spawns.iter_mut().find(|a| a.npc.name == npc_name);
+        if rule.is_none() {
+            return format!{"There is no {} spawning here, make one with \\npc.", npc_name};
+        }
+        let reply = edit(rule.unwrap());
+        room.npcs.retain(|a| a.name != npc_name);
+        return reply;
+    }
+    "Not in a room?".into()
+}
+
+pub async fn set_npc(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let npc_name = arg!(args.object_name("name"));
+    let level = arg!(args.integer("level"));
+    let health = arg!(args.integer("health"));
+    let damage = arg!(args.integer("damage"));
+    arg!(args.finish());
+    let npc = Npc::new(npc_name.clone(), level, health, damage);
+    if let Err(e) = skills::check_caps(&npc.requirement(&server_state.config), &server_state.config) {
+        return args.error(e).to_string();
+    }
+    if health < 1 || damage < 1 {
+        return args.error("Health and damage have to be at least 1.".into()).to_string();
+    }
+
+    let room = server_state.get_room(&client.lock().await.current_room).await;
+    if let Some(room) = room {
+        let mut room = room.lock().await;
+        if !room.spawns.iter().any(|a| a.npc.name == npc_name) {
+            room.spawns.push(SpawnRule::new(npc));
+            return format!{"The {} will spawn here, see \\npc_spawn, \\npc_describe, \\npc_loot and \\npc_script.", npc_name};
         }
-        return format!{"Not a valid object."};
+    } else {
+        return "Not in a room?".into();
     }
-    return format!{"Not in a room?"};
+    edit_spawn_rule(&server_state, &client, &npc_name, |rule| {
+        rule.npc.level = level;
+        rule.npc.health = health;
+        rule.npc.max_health = health;
+        rule.npc.damage = damage;
+        format!{"Updated the {}.", npc_name}
+    }).await
 }
 
-pub async fn add_action(input: &String, server_state: Arc<ServerState>, client: ClientPointer) -> String {
-    lazy_static! {
-        // We have quotes here because object name may contain spaces, : to name the action
-        static ref ADD_ACTION: Regex = Regex::new(r#"\\action (.+):(.+):(.+)"#).unwrap();
+pub async fn set_npc_spawn(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let npc_name = arg!(args.object_name("name"));
+    let max = arg!(args.integer("max"));
+    let respawn_secs = arg!(args.integer("respawn secs"));
+    arg!(args.finish());
+    let max_respawn_secs = server_state.config.max_npc_respawn_secs;
+    if !(0..=20).contains(&max) || respawn_secs < 1 || respawn_secs as u64 > max_respawn_secs {
+        return args.error(format!{"Max has to be between 0 and 20 and respawn between 1 and {} seconds.", max_respawn_secs}).to_string();
     }
+    edit_spawn_rule(&server_state, &client, &npc_name, |rule| {
+        rule.max = max as usize;
+        rule.respawn_secs = respawn_secs as u64;
+        format!{"Up to {} of the {} here, each back {} seconds after it dies.", max, npc_name, respawn_secs}
+    }).await
+}
 
-    if !ADD_ACTION.is_match(&input) {
-        return String::from("\\action <object name>:<action name>:<action string>");
+pub async fn describe_npc(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let npc_name = arg!(args.object_name("name"));
+    let description = arg!(args.rest("description"));
+    edit_spawn_rule(&server_state, &client, &npc_name, |rule| {
+        rule.npc.display = description;
+        "Done".into()
+    }).await
+}
+
+pub async fn add_npc_loot(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let npc_name = arg!(args.object_name("name"));
+    let template = arg!(args.word("template"));
+    let chance = arg!(args.integer("chance"));
+    arg!(args.finish());
+    if !(1..=100).contains(&chance) {
+        return args.error("Chance is a percent, between 1 and 100.".into()).to_string();
     }
+    if !server_state.item_templates.lock().unwrap().contains_key(&template) {
+        return format!{"There is no item template called {}.", template};
+    }
+    edit_spawn_rule(&server_state, &client, &npc_name, |rule| {
+        rule.npc.loot.retain(|a| a.template != template);
+        rule.npc.loot.push(Loot { template: template.clone(), chance });
+        format!{"The {} drops a {} {}% of the time.", npc_name, template, chance}
+    }).await
+}
 
-    let captures = ADD_ACTION.captures(&input).unwrap();
-    let object_name = captures.get(1).unwrap().as_str();
-    let action_name = captures.get(2).unwrap().as_str();
-    let action_string = captures.get(3).unwrap().as_str();
+pub async fn set_npc_script(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let npc_name = arg!(args.object_name("name"));
+    let script = arg!(args.identifier("script"));
+    arg!(args.finish());
+    let script = if script == "none" { None } else { Some(script) };
+    edit_spawn_rule(&server_state, &client, &npc_name, |rule| {
+        rule.npc.script = script;
+        "Done".into()
+    }).await
+}
 
-    let room = server_state.get_room(&client.lock().await.current_room);
+pub async fn remove_npc(input: &str, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let npc_name = arg!(args.object_name("name"));
+    arg!(args.finish());
+    let room = server_state.get_room(&client.lock().await.current_room).await;
     if let Some(room) = room {
         let mut room = room.lock().await;
-        let game_object_ref = room.objects.get_mut(object_name.into());
-        if let Some(game_object_ref) = game_object_ref {
-            game_object_ref.actions.insert(action_name.into(),
-                GameAction::parse_from_string(action_string.into()));
-            return format!{"Done"};
+        let before = room.spawns.len();
+        room.spawns.retain(|a| a.npc.name != npc_name);
+        room.npcs.retain(|a| a.name != npc_name);
+        if room.spawns.len() == before {
+            return format!{"There is no {} spawning here.", npc_name};
         }
-        return format!{"Not a valid object."};
+        return format!{"The {} will no longer spawn here.", npc_name};
     }
-    return format!{"Not in a room?"};
+    "Not in a room?".into()
 }
 
-pub async fn handle_touch(_input: &String, _server_state: Arc<ServerState>, _my_client: ClientPointer) -> String {
-    lazy_static! {
-        // i for interact, used to be touch changed so its not so annoying to type
-        // i <name> <action>
-        static ref OBJECT_USE_REGEX: Regex = Regex::new("i (.+) (.+)").unwrap();
+pub async fn set_template(input: &str, server_state: Arc<ServerState>, _client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let name = arg!(args.identifier("template"));
+    let difficulty = arg!(args.integer("difficulty"));
+    let level = arg!(args.integer("level"));
+    let stat = arg!(args.optional_word());
+    arg!(args.finish());
+    if let Err(e) = items::check_caps(difficulty, level, &server_state.config) {
+        return args.error(e).to_string();
     }
 
-    if !OBJECT_USE_REGEX.is_match(_input) {
-        return String::from("i <object name> <action>");
+    let created = {
+        let mut templates = server_state.item_templates.lock().unwrap();
+        let created = !templates.contains_key(&name);
+        let template = templates.entry(name.clone()).or_insert_with(|| ItemTemplate::new(name.clone(), difficulty, level));
+        template.difficulty = difficulty;
+        template.level = level;
+        if let Some(stat) = stat {
+            template.stat = stat;
+        }
+        created
+    };
+    if let Err(e) = server_state.save_templates().await {
+        return format!{"Template changed but saving failed: {}", e};
+    }
+    if created {
+        return format!{"Created template {}, give it names with \\template_name and descriptions with \\template_describe.", name};
     }
+    format!{"Updated template {}.", name}
+}
 
-    let captures = OBJECT_USE_REGEX.captures(&_input).unwrap();
-    let object_name = captures.get(1).unwrap().as_str();
-    let object_action = captures.get(2).unwrap().as_str();
+// Handles \template_name and \template_describe, both add a line to one of the template's pools
+pub async fn add_template_text(input: &str, server_state: Arc<ServerState>, _client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let name = arg!(args.word("template"));
+    let text = arg!(args.rest("text"));
+    let is_name = input.starts_with("\\template_name");
+    {
+        let mut templates = server_state.item_templates.lock().unwrap();
+        let template = match templates.get_mut(&name) {
+            Some(template) => template,
+            None => return format!{"There is no item template called {}.", name}
+        };
+        if is_name {
+            template.names.push(text);
+        } else {
+            template.descriptions.push(text);
+        }
+    }
+    if let Err(e) = server_state.save_templates().await {
+        return format!{"Template changed but saving failed: {}", e};
+    }
+    "Done".into()
+}
+
+pub async fn list_templates(input: &str, server_state: Arc<ServerState>, _client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let name = arg!(args.optional_word());
+    arg!(args.finish());
+    let templates = server_state.item_templates.lock().unwrap();
+    if let Some(name) = name {
+        return match templates.get(&name) {
+            Some(template) => format!{"@D{}: {} = {} + {} + rng(0, {})\nNames: {}\nDescriptions:\n{}",
+                template.name, template.stat, template.difficulty, template.level, template.level / 5,
+                template.names.join(", "), template.descriptions.join("\n")},
+            None => format!{"There is no item template called {}.", name}
+        };
+    }
+    if templates.is_empty() {
+        return "There are no item templates yet.".into();
+    }
+    let mut names: Vec<&String> = templates.keys().collect();
+    names.sort();
+    names.iter().fold("@DItem templates:".into(), |a, b| format!{"{}\n{}", a, b})
+}
 
-    let room = _server_state.get_room(&_my_client.lock().await.current_room);
+pub async fn list_timers(input: &str, server_state: Arc<ServerState>, _client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let player = arg!(args.optional_word());
+    arg!(args.finish());
+    let now = unix_now();
+    let timers = server_state.timers.lock().unwrap();
+    let lines: Vec<String> = timers.iter()
+        .filter(|a| player.as_ref().is_none_or(|b| &a.player == b))
+        .map(|a| {
+            let what = match &a.action {
+                TimerAction::Call(function) => format!{"calls {} in {}", function, a.script},
+                TimerAction::Message(text) => format!{"tells them \"{}\" from {}", text, a.script}
+            };
+            format!{"{} - {} in {}s {}", a.handle, a.player, a.due.saturating_sub(now), what}
+        })
+        .collect();
+    if lines.is_empty() {
+        return "No timers are waiting.".into();
+    }
+    format!{"@DTimers:\n{}", lines.join("\n")}
+}
+
+pub async fn cancel_timer(input: &str, server_state: Arc<ServerState>, _client: ClientPointer) -> String {
+    let mut args = Args::parse(input);
+    let handle = arg!(args.integer("handle"));
+    arg!(args.finish());
+    let mut timers = server_state.timers.lock().unwrap();
+    let before = timers.len();
+    timers.retain(|a| a.handle as i64 != handle);
+    if timers.len() == before {
+        return format!{"There is no timer {}.", handle};
+    }
+    "Done".into()
+}
+
+pub async fn handle_touch(_input: &str, _server_state: Arc<ServerState>, _my_client: ClientPointer) -> String {
+    // i for interact, used to be touch changed so its not so annoying to type
+    let mut args = Args::parse(_input);
+    let object_name = arg!(args.object_name("object"));
+    let object_action = arg!(args.word("action"));
+    arg!(args.finish());
+
+    let room = _server_state.get_room(&_my_client.lock().await.current_room).await;
     if room.is_none() {
         return String::from("You belong to an invalid room.");
     }
 
     let room_un = room.unwrap();
-    let room_ref = room_un.lock().await;
-    if let Some(object) = room_ref.objects.get(object_name) {
-        let some_action = object.actions.get(object_action);
-        if let Some(action) = some_action {
-            return action.handle(_my_client.clone(), room_un.clone(), _server_state.runtime.clone()).await;
-        } else {
-            return "The object does not have that action".into();
+    let find = |object: &GameObject| (object.actions.get(&object_action).cloned(), object.requirements.get(&object_action).cloned());
+    let found = room_un.lock().await.objects.get(&object_name).map(find);
+    // Things they carry work anywhere
+    let found = match found {
+        Some(found) => Some(found),
+        None => _my_client.lock().await.carried(&object_name).map(find)
+    };
+    let (action, requirement) = match found {
+        Some((Some(action), requirement)) => (action, requirement),
+        Some((None, _)) => return "The object does not have that action".into(),
+        None => return "The object does not exist".into()
+    };
+
+    if let Some(requirement) = &requirement {
+        let level = _my_client.lock().await.skill_level(&requirement.skill);
+        if level < requirement.level {
+            return format!{"You need level {} {} to do that, you are level {}.", requirement.level, requirement.skill, level};
         }
-    } else {
-        return "The object does not exist".into();
     }
-}
+    let source = format!{"{} {} for {}", object_name, object_action, _my_client.lock().await.name};
+    // Only actions that went through are worth xp
+    let result = match action.handle(_my_client.clone(), room_un.clone(), _server_state.clone(), &source).await {
+        Ok(result) => result,
+        Err(error) => return error
+    };
+    if requirement.is_none() {
+        return result;
+    }
 
-pub async fn look(_input: &String, _server_state: Arc<ServerState>, _my_client: ClientPointer) -> String {
-    lazy_static! {
-        // touch <name> <action>
-        static ref LOOK_REGEX: Regex = Regex::new("look (.+)").unwrap();
+    let requirement = requirement.unwrap();
+    let mut client_ref = _my_client.lock().await;
+    let xp = skills::xp_reward(&requirement, client_ref.skill_level(&requirement.skill), &_server_state.config);
+    if xp <= 0 {
+        return result;
+    }
+    match client_ref.add_xp(&requirement.skill, xp) {
+        Some(level) => format!{"{}\n@C+{} {} xp. You reached level {} {}!", result, xp, requirement.skill, level, requirement.skill},
+        None => format!{"{}\n@C+{} {} xp.", result, xp, requirement.skill}
     }
+}
 
-    let room = _server_state.get_room(&_my_client.lock().await.current_room);
+pub async fn look(_input: &str, _server_state: Arc<ServerState>, _my_client: ClientPointer) -> String {
+    let mut args = Args::parse(_input);
+    let object_name = arg!(args.optional_rest());
+
+    let current_room = _my_client.lock().await.current_room.clone();
+    let room = _server_state.get_room(&current_room).await;
     if room.is_none() {
         return String::from("You belong to an invalid room.");
     }
 
+    // Grab who is here before locking the room, clients_in_room needs it too
+    let mut others = vec![];
+    if object_name.is_none() {
+        let role = _my_client.lock().await.role;
+        for other in _server_state.clients_in_room(&current_room).await {
+            if Arc::ptr_eq(&other, &_my_client) {
+                continue;
+            }
+            let other_ref = other.lock().await;
+            if _server_state.can_see(role, &other_ref) {
+                others.push(other_ref.name.clone());
+            }
+        }
+        others.sort();
+    }
+
     let room_un = room.unwrap();
     let room_ref = room_un.lock().await;
-    if !LOOK_REGEX.is_match(_input) {
+    if object_name.is_none() {
         // When displaying room code we also want to show available objects and links in room
         let link_string = room_ref.links.iter().fold("\n@CLinks:\n".into(), |a, b| format!{"{}\n{}\n", a, b});
         let objects_string = room_ref.objects.values().map(|a| &a.name).fold("\n\n@CObjects:".into(), |a, b| format!{"{}\n{}\n", a, b});
-        return format!{"{}{}{}", room_ref.display.clone(), objects_string, link_string};
+        let others_string = if others.is_empty() { String::new() } else { format!{"\n@CAlso here: {}", others.join(", ")} };
+        let npc_names: Vec<&str> = room_ref.npcs.iter().map(|a| a.name.as_str()).collect();
+        let npcs_string = if npc_names.is_empty() { String::new() } else { format!{"\n@BCreatures: {}", npc_names.join(", ")} };
+        return format!{"{}{}{}{}{}", room_ref.display.clone(), objects_string, link_string, others_string, npcs_string};
     }
-    let captures = LOOK_REGEX.captures(&_input).unwrap();
-    let object_name = captures.get(1).unwrap().as_str();
-    if let Some(object) = room_ref.objects.get(object_name) {
+    let object_name = object_name.unwrap();
+    if let Some(object) = room_ref.objects.get(&object_name) {
         return object.display.clone();
-    } else {
-        return "The object does not exist".into();
+    }
+    if let Some(npc) = room_ref.npcs.iter().find(|a| a.name == object_name) {
+        return format!{"{}\nLevel {}, health {}/{}.", npc.display, npc.level, npc.health, npc.max_health};
+    }
+    drop(room_ref);
+    match _my_client.lock().await.carried(&object_name) {
+        Some(object) => object.display.clone(),
+        None => "The object does not exist".into()
     }
 }
@@ -0,0 +1,565 @@
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use crate::{lazy_static, args::Args, command_handlers, states::{ServerState, ClientPointer, Role}};
+
+pub type CommandFuture = Pin<Box<dyn Future<Output = String> + Send>>;
+pub type CommandFn = fn(String, Arc<ServerState>, ClientPointer) -> CommandFuture;
+
+// Turns an `async fn(&str, Arc<ServerState>, ClientPointer) -> String` into a CommandFn
+macro_rules! handler {
+    ($f:path) => {
+        |input: String, server_state: Arc<ServerState>, client: ClientPointer| -> CommandFuture {
+            Box::pin(async move { $f(&input, server_state, client).await })
+        }
+    }
+}
+
+pub struct Command {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub role: Role,
+    pub handler: CommandFn
+}
+
+#[derive(Default)]
+pub struct CommandRegistry {
+    commands: Vec<Command>
+}
+
+lazy_static! {
+    pub static ref COMMANDS: CommandRegistry = CommandRegistry::with_defaults();
+}
+
+impl CommandRegistry {
+    pub fn register(&mut self, command: Command) {
+        self.commands.push(command);
+    }
+
+    pub fn find(&self, word: &str) -> Option<&Command> {
+        self.commands.iter().find(|a| a.name == word || a.aliases.contains(&word))
+    }
+
+    // Everything this role can run, one usage line each
+    pub fn help(&self, role: Role) -> String {
+        let mut lines = vec![String::from("@DCommands:")];
+        for command in self.commands.iter().filter(|a| a.role <= role) {
+            lines.push(format!{"{} - {}", command.usage, command.help});
+        }
+        lines.push("Type help <command> for more about one of them.".into());
+        lines.push("Quote names that have spaces, like i \"old sign\" read.".into());
+        lines.join("\n")
+    }
+
+    pub fn help_for(&self, word: &str, role: Role) -> String {
+        match self.find(word) {
+            Some(command) if command.role <= role => {
+                let mut text = format!{"@D{}\n{}", command.usage, command.help};
+                if !command.aliases.is_empty() {
+                    text = format!{"{}\nAliases: {}", text, command.aliases.join(", ")};
+                }
+                if command.role > Role::Player {
+                    text = format!{"{}\nRequires: {}", text, command.role};
+                }
+                text
+            },
+            _ => format!{"There is no command called {}.", word}
+        }
+    }
+
+    // Closest name or alias this role can use, if any is near enough to be a typo
+    pub fn suggest(&self, word: &str, role: Role) -> Option<&'static str> {
+        self.commands.iter()
+            .filter(|a| a.role <= role)
+            .flat_map(|a| std::iter::once(a.name).chain(a.aliases.iter().copied()))
+            .map(|a| (edit_distance(word, a), a))
+            .filter(|a| a.0 <= 2 && a.0 < word.len())
+            .min_by_key(|a| a.0)
+            .map(|a| a.1)
+    }
+
+    pub async fn dispatch(&self, input: String, server_state: Arc<ServerState>, client: ClientPointer) -> String {
+        let word = &input[..input.find(' ').unwrap_or(input.len())];
+        if word.is_empty() {
+            return "The room is quiet.".into();
+        }
+        let role = client.lock().await.role;
+        let command = self.find(word);
+        // Channels they are on work as commands, ooc hello is chat ooc hello
+        if command.is_none() && client.lock().await.channels.iter().any(|a| a == word) {
+            return command_handlers::chat(&format!{"chat {}", input}, server_state, client).await;
+        }
+        if command.is_none() {
+            return match self.suggest(word, role) {
+                Some(suggestion) => format!{"Unknown command {}, did you mean {}?", word, suggestion},
+                None => format!{"Unknown command {}, type help for a list.", word}
+            };
+        }
+        let command = command.unwrap();
+        if role < command.role {
+            let article = if command.role == Role::Admin { "an" } else { "a" };
+            return format!{"@BYou need to be {} {} to use {}.", article, command.role, command.name};
+        }
+        // Handlers parse their own input so hand them the real name even if an alias was typed
+        let input = format!{"{}{}", command.name, &input[word.len()..]};
+        (command.handler)(input, server_state, client).await
+    }
+
+    pub fn with_defaults() -> Self {
+        let mut registry = Self::default();
+        registry.register(Command {
+            name: "help",
+            aliases: &["?"],
+            usage: "help [command]",
+            help: "Lists commands, or explains one of them.",
+            role: Role::Player,
+            handler: handler!(help)
+        });
+        registry.register(Command {
+            name: "look",
+            aliases: &["l"],
+            usage: "look [object]",
+            help: "Describes the room, or reads an object's display text.",
+            role: Role::Player,
+            handler: handler!(command_handlers::look)
+        });
+        registry.register(Command {
+            name: "i",
+            aliases: &["interact"],
+            usage: "i <object> <action>",
+            help: "Interacts with an object in the room.",
+            role: Role::Player,
+            handler: handler!(command_handlers::handle_touch)
+        });
+        registry.register(Command {
+            name: "get",
+            aliases: &["take"],
+            usage: "get <object>",
+            help: "Picks up an object from the room.",
+            role: Role::Player,
+            handler: handler!(command_handlers::get_item)
+        });
+        registry.register(Command {
+            name: "drop",
+            aliases: &[],
+            usage: "drop <object>",
+            help: "Puts something you carry down in the room.",
+            role: Role::Player,
+            handler: handler!(command_handlers::drop_item)
+        });
+        registry.register(Command {
+            name: "give",
+            aliases: &[],
+            usage: "give <player> <object>",
+            help: "Hands something you carry to a player in the same room.",
+            role: Role::Player,
+            handler: handler!(command_handlers::give_item)
+        });
+        registry.register(Command {
+            name: "inventory",
+            aliases: &["inv"],
+            usage: "inventory",
+            help: "Lists what you are carrying and what you have earned.",
+            role: Role::Player,
+            handler: handler!(command_handlers::inventory)
+        });
+        registry.register(Command {
+            name: "skills",
+            aliases: &[],
+            usage: "skills",
+            help: "Lists your skills, their levels and xp.",
+            role: Role::Player,
+            handler: handler!(command_handlers::list_skills)
+        });
+        registry.register(Command {
+            name: "attack",
+            aliases: &["kill"],
+            usage: "attack <target>",
+            help: "Swings at someone in the room, each swing costs stamina.",
+            role: Role::Player,
+            handler: handler!(command_handlers::attack)
+        });
+        registry.register(Command {
+            name: "equip",
+            aliases: &["wield"],
+            usage: "equip <object>",
+            help: "Wields something you carry, its power adds to your damage.",
+            role: Role::Player,
+            handler: handler!(command_handlers::equip)
+        });
+        registry.register(Command {
+            name: "unequip",
+            aliases: &[],
+            usage: "unequip",
+            help: "Puts your weapon back in your inventory.",
+            role: Role::Player,
+            handler: handler!(command_handlers::unequip)
+        });
+        registry.register(Command {
+            name: "score",
+            aliases: &["stats"],
+            usage: "score",
+            help: "Shows your health, stamina and weapon.",
+            role: Role::Player,
+            handler: handler!(command_handlers::score)
+        });
+        registry.register(Command {
+            name: "idle",
+            aliases: &[],
+            usage: "idle [skill|stop]",
+            help: "Picks a skill to practise while you are logged out.",
+            role: Role::Player,
+            handler: handler!(command_handlers::set_idle_activity)
+        });
+        registry.register(Command {
+            name: "move",
+            aliases: &["go"],
+            usage: "move <area>",
+            help: "Moves into a linked area.",
+            role: Role::Player,
+            handler: handler!(command_handlers::move_into)
+        });
+        registry.register(Command {
+            name: "say",
+            aliases: &[],
+            usage: "say <message>",
+            help: "Says something to everyone in the room.",
+            role: Role::Player,
+            handler: handler!(command_handlers::say)
+        });
+        registry.register(Command {
+            name: "emote",
+            aliases: &["me"],
+            usage: "emote <action>",
+            help: "Acts something out, emote waves shows as <your name> waves.",
+            role: Role::Player,
+            handler: handler!(command_handlers::emote)
+        });
+        registry.register(Command {
+            name: "whisper",
+            aliases: &["tell"],
+            usage: "whisper <player> <message>",
+            help: "Says something only one player hears, wherever they are.",
+            role: Role::Player,
+            handler: handler!(command_handlers::whisper)
+        });
+        registry.register(Command {
+            name: "reply",
+            aliases: &["r"],
+            usage: "reply <message>",
+            help: "Whispers back to whoever last whispered to you.",
+            role: Role::Player,
+            handler: handler!(command_handlers::reply)
+        });
+        registry.register(Command {
+            name: "channels",
+            aliases: &[],
+            usage: "channels",
+            help: "Lists the chat channels you can join.",
+            role: Role::Player,
+            handler: handler!(command_handlers::list_channels)
+        });
+        registry.register(Command {
+            name: "join",
+            aliases: &[],
+            usage: "join <channel>",
+            help: "Subscribes to a channel and shows what was said there lately.",
+            role: Role::Player,
+            handler: handler!(command_handlers::join_channel)
+        });
+        registry.register(Command {
+            name: "leave",
+            aliases: &[],
+            usage: "leave <channel>",
+            help: "Unsubscribes from a channel.",
+            role: Role::Player,
+            handler: handler!(command_handlers::leave_channel)
+        });
+        registry.register(Command {
+            name: "mute",
+            aliases: &[],
+            usage: "mute <channel>",
+            help: "Stops showing a channel without leaving it.",
+            role: Role::Player,
+            handler: handler!(command_handlers::mute_channel)
+        });
+        registry.register(Command {
+            name: "unmute",
+            aliases: &[],
+            usage: "unmute <channel>",
+            help: "Shows a muted channel again.",
+            role: Role::Player,
+            handler: handler!(command_handlers::mute_channel)
+        });
+        registry.register(Command {
+            name: "chat",
+            aliases: &[],
+            usage: "chat <channel> <message>",
+            help: "Talks on a channel you joined, <channel> <message> works too.",
+            role: Role::Player,
+            handler: handler!(command_handlers::chat)
+        });
+        registry.register(Command {
+            name: "who",
+            aliases: &[],
+            usage: "who",
+            help: "Lists who is online, where they are and how long they have been idle.",
+            role: Role::Player,
+            handler: handler!(command_handlers::who)
+        });
+        registry.register(Command {
+            name: "finger",
+            aliases: &[],
+            usage: "finger <player>",
+            help: "Shows when a player was last around.",
+            role: Role::Player,
+            handler: handler!(command_handlers::finger)
+        });
+        registry.register(Command {
+            name: "login",
+            aliases: &[],
+            usage: "login <username> <password>",
+            help: "Switches to another of your characters.",
+            role: Role::Player,
+            handler: handler!(command_handlers::login)
+        });
+        registry.register(Command {
+            name: "password",
+            aliases: &[],
+            usage: "password <old password> <new password>",
+            help: "Changes your password.",
+            role: Role::Player,
+            handler: handler!(command_handlers::change_password)
+        });
+        registry.register(Command {
+            name: "quit",
+            aliases: &[],
+            usage: "quit",
+            help: "Saves and disconnects.",
+            role: Role::Player,
+            // The connection loop catches quit before it gets here
+            handler: handler!(quit)
+        });
+        registry.register(Command {
+            name: "\\add",
+            aliases: &[],
+            usage: "\\add <object name>",
+            help: "Adds an object to the room.",
+            role: Role::Builder,
+            handler: handler!(command_handlers::add_object)
+        });
+        registry.register(Command {
+            name: "\\describe",
+            aliases: &[],
+            usage: "\\describe <object name> <description>",
+            help: "Sets the text look shows for an object.",
+            role: Role::Builder,
+            handler: handler!(command_handlers::describe_object)
+        });
+        registry.register(Command {
+            name: "\\action",
+            aliases: &[],
+            usage: "\\action <object name> <action name> <action string>",
+            help: "Gives an object an action, e.g. \\action sign read PrintText hello, RunScript test or SpawnItem sword.",
+            role: Role::Builder,
+            handler: handler!(command_handlers::add_action)
+        });
+        registry.register(Command {
+            name: "\\require",
+            aliases: &[],
+            usage: "\\require <object name> <action name> <skill|none> [difficulty] [level]",
+            help: "Makes an action need a level in a skill and teach difficulty * level xp, none removes it.",
+            role: Role::Builder,
+            handler: handler!(command_handlers::set_requirement)
+        });
+        registry.register(Command {
+            name: "\\portable",
+            aliases: &[],
+            usage: "\\portable <object name>",
+            help: "Toggles whether players can pick an object up, objects start fixed in place.",
+            role: Role::Builder,
+            handler: handler!(command_handlers::toggle_portable)
+        });
+        registry.register(Command {
+            name: "\\npc",
+            aliases: &[],
+            usage: "\\npc <name> <level> <health> <damage>",
+            help: "Makes an npc spawn in the room, or changes it.",
+            role: Role::Builder,
+            handler: handler!(command_handlers::set_npc)
+        });
+        registry.register(Command {
+            name: "\\npc_spawn",
+            aliases: &[],
+            usage: "\\npc_spawn <name> <max> <respawn secs>",
+            help: "Sets how many of an npc the room holds and how soon each comes back.",
+            role: Role::Builder,
+            handler: handler!(command_handlers::set_npc_spawn)
+        });
+        registry.register(Command {
+            name: "\\npc_describe",
+            aliases: &[],
+            usage: "\\npc_describe <name> <description>",
+            help: "Sets the text look shows for an npc.",
+            role: Role::Builder,
+            handler: handler!(command_handlers::describe_npc)
+        });
+        registry.register(Command {
+            name: "\\npc_loot",
+            aliases: &[],
+            usage: "\\npc_loot <name> <template> <chance>",
+            help: "Gives an npc a percent chance to drop an item from a template.",
+            role: Role::Builder,
+            handler: handler!(command_handlers::add_npc_loot)
+        });
+        registry.register(Command {
+            name: "\\npc_script",
+            aliases: &[],
+            usage: "\\npc_script <name> <script|none>",
+            help: "Runs a script's behave fn every npc tick, it returns attack, an emote or nothing.",
+            role: Role::Builder,
+            handler: handler!(command_handlers::set_npc_script)
+        });
+        registry.register(Command {
+            name: "\\npc_remove",
+            aliases: &[],
+            usage: "\\npc_remove <name>",
+            help: "Stops an npc spawning in the room and clears the ones here.",
+            role: Role::Builder,
+            handler: handler!(command_handlers::remove_npc)
+        });
+        registry.register(Command {
+            name: "\\timers",
+            aliases: &[],
+            usage: "\\timers [player]",
+            help: "Lists script timers waiting to fire, started by after and tell_after in scripts.",
+            role: Role::Builder,
+            handler: handler!(command_handlers::list_timers)
+        });
+        registry.register(Command {
+            name: "\\timer_cancel",
+            aliases: &[],
+            usage: "\\timer_cancel <handle>",
+            help: "Cancels a waiting script timer.",
+            role: Role::Builder,
+            handler: handler!(command_handlers::cancel_timer)
+        });
+        registry.register(Command {
+            name: "\\template",
+            aliases: &[],
+            usage: "\\template <template> <difficulty> <level> [stat]",
+            help: "Creates or changes an item template, items roll stat = difficulty + level + rng(0, level / 5).",
+            role: Role::Builder,
+            handler: handler!(command_handlers::set_template)
+        });
+        registry.register(Command {
+            name: "\\template_name",
+            aliases: &[],
+            usage: "\\template_name <template> <item name>",
+            help: "Adds a name items from the template can roll.",
+            role: Role::Builder,
+            handler: handler!(command_handlers::add_template_text)
+        });
+        registry.register(Command {
+            name: "\\template_describe",
+            aliases: &[],
+            usage: "\\template_describe <template> <description>",
+            help: "Adds a description items from the template can roll.",
+            role: Role::Builder,
+            handler: handler!(command_handlers::add_template_text)
+        });
+        registry.register(Command {
+            name: "\\templates",
+            aliases: &[],
+            usage: "\\templates [template]",
+            help: "Lists item templates, or shows one of them.",
+            role: Role::Builder,
+            handler: handler!(command_handlers::list_templates)
+        });
+        registry.register(Command {
+            name: "\\link",
+            aliases: &[],
+            usage: "\\link <to place>",
+            help: "Links the room to another, creating it if needed.",
+            role: Role::Builder,
+            handler: handler!(command_handlers::add_link)
+        });
+        registry.register(Command {
+            name: "\\script",
+            aliases: &[],
+            usage: "\\script <name>:<contents>",
+            help: "Uploads a dyon script, the client fills in the contents from a file.",
+            role: Role::Builder,
+            handler: handler!(command_handlers::upload_script)
+        });
+        registry.register(Command {
+            name: "\\reload",
+            aliases: &[],
+            usage: "\\reload <script>",
+            help: "Parses a script again, scripts also reload on their own when the file changes.",
+            role: Role::Builder,
+            handler: handler!(command_handlers::reload_script)
+        });
+        registry.register(Command {
+            name: "\\edit",
+            aliases: &[],
+            usage: "\\edit",
+            help: "Toggles edit mode, editors are hidden from players unless invisible_editors is off.",
+            role: Role::Builder,
+            handler: handler!(command_handlers::toggle_edit_mode)
+        });
+        registry.register(Command {
+            name: "\\save",
+            aliases: &[],
+            usage: "\\save",
+            help: "Saves the world and your character now.",
+            role: Role::Builder,
+            handler: handler!(command_handlers::save)
+        });
+        registry.register(Command {
+            name: "\\grant",
+            aliases: &[],
+            usage: "\\grant <username> <player|builder|admin>",
+            help: "Changes an account's role.",
+            role: Role::Admin,
+            handler: handler!(command_handlers::grant)
+        });
+        registry.register(Command {
+            name: "\\reset_password",
+            aliases: &[],
+            usage: "\\reset_password <username> <new password>",
+            help: "Sets a new password for an account, for players who lost theirs or accounts that never had one.",
+            role: Role::Admin,
+            handler: handler!(command_handlers::reset_password)
+        });
+        registry
+    }
+}
+
+async fn help(input: &str, _server_state: Arc<ServerState>, client: ClientPointer) -> String {
+    let role = client.lock().await.role;
+    let mut args = Args::parse(input);
+    match arg!(args.optional_word()) {
+        Some(word) => COMMANDS.help_for(&word, role),
+        None => COMMANDS.help(role)
+    }
+}
+
+async fn quit(_input: &str, _server_state: Arc<ServerState>, _client: ClientPointer) -> String {
+    "Goodbye.".into()
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitute = previous[j] + (a_char != *b_char) as usize;
+            current.push(substitute.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
@@ -0,0 +1,168 @@
+use std::{fs::OpenOptions, io::BufReader};
+
+use serde_derive::{Serialize, Deserialize};
+
+use crate::states::Role;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+    #[default]
+    Json,
+    Sqlite,
+    // Nothing touches the disk, handy for tests and throwaway servers
+    Memory
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ChannelConfig {
+    pub name: String,
+    // Lowest role that can join, talk on or hear the channel
+    pub min_role: Role,
+    // Lines replayed to someone joining
+    pub history: usize,
+    // New accounts start subscribed
+    pub auto_join: bool
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            min_role: Role::Player,
+            history: 20,
+            auto_join: false
+        }
+    }
+}
+
+impl ChannelConfig {
+    fn new(name: &str, min_role: Role, auto_join: bool) -> Self {
+        Self { name: name.into(), min_role, auto_join, ..Default::default() }
+    }
+}
+
+// Read once at startup from config.json, every field can be left out
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub store: StoreKind,
+    pub database_dir: String,
+    // How many old copies of world.json the json store keeps in database/backups
+    pub world_backups: usize,
+    // Seconds between autosaves of changed rooms and players, 0 turns it off
+    pub autosave_secs: u64,
+    // Accounts that are always made admin when they log in
+    pub admins: Vec<String>,
+    // Builders in edit mode (\edit) are left out of who and room announcements
+    pub invisible_editors: bool,
+    // Seeds item rolls, leave it out to seed from the clock. Fights and npcs roll on their own generator
+    // so a fixed seed drops the same items whatever else happens
+    pub item_seed: Option<u64>,
+    // Caps on item templates, so nobody can mint overpowered loot
+    pub max_item_difficulty: i64,
+    pub max_item_level: i64,
+    // Caps on what builders can ask of an action, so nobody can build an xp farm
+    pub max_action_difficulty: i64,
+    pub max_action_level: i64,
+    pub max_action_xp: i64,
+    // Offline progress for players with an idle activity, see offline.rs
+    pub offline_min_minutes: u64,
+    pub offline_max_hours: u64,
+    pub offline_xp_per_hour: i64,
+    pub offline_resources_per_hour: i64,
+    pub offline_resource: String,
+    // A script in dyon/ with an offline fn to use instead of the per hour rates
+    pub offline_script: Option<String>,
+    // Where players wake up after dying
+    pub respawn_room: String,
+    pub attack_cooldown_ms: u64,
+    pub attack_stamina: i64,
+    // Health and stamina come back this much every regen_secs, 0 turns it off
+    pub regen_secs: u64,
+    pub health_regen: i64,
+    pub stamina_regen: i64,
+    // Every script run is stopped once it breaks one of these
+    pub script_time_ms: u64,
+    // On top of what its worker already holds, only enforced on linux
+    pub script_memory_kb: usize,
+    // Tells, moves, objects and timers one run can ask for
+    pub script_max_effects: usize,
+    // Longest delay after and tell_after can ask for, longer ones are cut down to it
+    pub script_max_timer_secs: u64,
+    // Scripts that can run at once, each in its own worker process, 0 uses one per cpu
+    pub script_workers: usize,
+    // Milliseconds per game tick, a tick that takes longer is logged as an overrun
+    pub tick_ms: u64,
+    // How often npcs spawn and run their behaviour scripts, 0 turns them off
+    pub npc_tick_secs: u64,
+    // Longest a builder can make a dead npc wait before it respawns
+    pub max_npc_respawn_secs: u64,
+    pub channels: Vec<ChannelConfig>
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            store: StoreKind::default(),
+            database_dir: "database".into(),
+            world_backups: 5,
+            autosave_secs: 60,
+            admins: vec![],
+            invisible_editors: true,
+            item_seed: None,
+            max_item_difficulty: 100,
+            max_item_level: 100,
+            max_action_difficulty: 10,
+            max_action_level: 100,
+            max_action_xp: 250,
+            offline_min_minutes: 5,
+            offline_max_hours: 12,
+            offline_xp_per_hour: 60,
+            offline_resources_per_hour: 20,
+            offline_resource: "coins".into(),
+            offline_script: None,
+            respawn_room: "nexus".into(),
+            attack_cooldown_ms: 1500,
+            attack_stamina: 5,
+            regen_secs: 10,
+            health_regen: 5,
+            stamina_regen: 5,
+            script_time_ms: 500,
+            script_memory_kb: 16 * 1024,
+            script_max_effects: 100,
+            script_max_timer_secs: 7 * 24 * 3600,
+            script_workers: 0,
+            tick_ms: 250,
+            npc_tick_secs: 5,
+            max_npc_respawn_secs: 24 * 3600,
+            channels: vec![
+                ChannelConfig::new("ooc", Role::Player, true),
+                ChannelConfig::new("newbie", Role::Player, true),
+                ChannelConfig::new("builders", Role::Builder, false)
+            ]
+        }
+    }
+}
+
+// For the caps builders are held to, Err says which one was broken
+pub fn check_cap(what: &str, value: i64, min: i64, max: i64) -> Result<(), String> {
+    if !(min..=max).contains(&value) {
+        return Err(format!{"{} has to be between {} and {}.", what, min, max});
+    }
+    Ok(())
+}
+
+impl ServerConfig {
+    pub fn load(path: &str) -> std::io::Result<Self> {
+        let file = OpenOptions::new().read(true).open(path);
+        if let Err(ref e) = file {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                return Ok(Self::default());
+            }
+        }
+        let config = serde_json::from_reader(BufReader::new(file?))?;
+        Ok(config)
+    }
+}
@@ -1,56 +1,89 @@
 use std::collections::HashMap;
-use std::ops::{Deref, DerefMut};
-use std::{io::ErrorKind, sync::Arc};
+use std::{io::ErrorKind, sync::Arc, time::SystemTime};
 
-use dyon::{error, Runtime, Module, Dfn, Type, load, dyon_fn, Variable, runtime::Flow, ast, embed::PushVariable};
+use dyon::{FnIndex, Module, Dfn, Type, load_str, dyon_fn, Variable, embed::PushVariable};
 use dyon::{dyon_macro_items, RustObject};
 use dyon::dyon_fn_pop;
-use tokio::sync::Mutex;
-
-use crate::states::ClientPointer;
-
-pub async fn load_and_run(path: &String, client_state: ClientPointer, runtime: &Arc<Mutex<Runtime>>) -> std::io::Result<(Option<Variable>, Flow)> {
-    let dyon_module= load_module(path)?;
-    let dyon_module = Arc::new(dyon_module);
-    let find = dyon_module.find_function(&Arc::new("home".into()), 0);
-    let data = client_state.try_lock().expect("Failed to lock data").client_script_states.clone();
-    let call_res = runtime.lock().await.call(&ast::Call {
-        args: vec![
-                ast::Expression::Variable(
-                    Box::new((range::Range::empty(0), (data as RustObject).push_var()))
-                )
-              ],
-        f_index: find,
-        custom_source: None,
-        info: Box::new(ast::CallInfo {
-            name: Arc::new("home".into()),
-            alias: None,
-            source_range: range::Range::empty(0)
-        })
-    }, &dyon_module);
-    if let Err(_) = call_res {
-        return Err(std::io::Error::new(ErrorKind::Other, "Dyon failed to run file"));
-    }
-    Ok(call_res.unwrap())
+
+use crate::config::ServerConfig;
+use crate::sandbox::{ScriptError, ScriptResult};
+use crate::script_api::{self, ScriptContext, ScriptEffect};
+use crate::states::{ClientPointer, ClientState, ServerState};
+use crate::storage::MemoryStore;
+use crate::timers::{self, TimerAction};
+
+// Parsed scripts by path, parsed again once the file on disk changes. Each sandbox worker keeps its own.
+#[derive(Default)]
+pub struct ModuleCache {
+    modules: std::sync::Mutex<HashMap<String, (FileVersion, Arc<Module>)>>
 }
 
-dyon_fn! {
-    fn test_func() {
+// Modified time and length, an edit within the same tick of the clock still has to change the length to be missed
+#[derive(PartialEq, Clone, Copy)]
+struct FileVersion(SystemTime, u64);
+
+impl ModuleCache {
+    pub fn get(&self, path: &str) -> std::io::Result<Arc<Module>> {
+        let metadata = std::fs::metadata(path)
+            .map_err(|e| std::io::Error::new(e.kind(), format!{"could not open {}, {}", path, e}))?;
+        let version = FileVersion(metadata.modified()?, metadata.len());
+        if let Some((cached, module)) = self.modules.lock().unwrap().get(path) {
+            if *cached == version {
+                return Ok(module.clone());
+            }
+        }
+        // Parsed without the lock held, at worst two callers both parse a changed file
+        let module = Arc::new(load_module(path)?);
+        self.modules.lock().unwrap().insert(path.into(), (version, module.clone()));
+        Ok(module)
+    }
+}
+
+pub async fn load_and_run(path: &str, client_state: ClientPointer, server_state: &ServerState, label: &str) -> ScriptResult {
+    call_with_state(path, "home", client_state, server_state, label).await
+}
+
+// Calls fn <function>(state) with the player's script state, along with whatever it asked the server to do
+pub async fn call_with_state(path: &str, function: &str, client_state: ClientPointer, server_state: &ServerState, label: &str) -> ScriptResult {
+    let context = script_api::snapshot(server_state, &client_state).await;
+    let data = client_state.lock().await.client_script_states.clone();
+    let args = vec![(data as RustObject).push_var()];
+    call(server_state, path, function, args, context, label).await
+}
+
+// Runs fn <function>(args) from the script at path in the sandbox, label says what it ran for in the log
+pub async fn call(server_state: &ServerState, path: &str, function: &str, args: Vec<Variable>, context: ScriptContext, label: &str) -> ScriptResult {
+    let result = server_state.sandbox.call(path, function, args, context).await;
+    if let Err(ScriptError::Stopped(reason)) = &result {
+        eprintln!("Stopped {} in {} for {}: {}", function, path, label, reason);
     }
+    result
 }
 
-fn get_client_state<'a>(v: &'a std::sync::MutexGuard<'_, dyn std::any::Any>) -> Option<&'a HashMap<String, String>> {
-    if let Some(val) = (*v.deref()).downcast_ref::<HashMap<String, String>>() {
-        return Some(val);
+// server run-script <path> [function], runs it once for a new player with the limits in config.json,
+// the way a RunScript action would, and prints what it returned
+pub async fn run_once(path: &str, function: &str) -> std::io::Result<()> {
+    let config = ServerConfig::load("config.json")?;
+    let server_state = ServerState::new(config, Box::new(MemoryStore::default()))?;
+    let client = ClientState::new(None).into_pointer();
+    match call_with_state(path, function, client, &server_state, "run-script").await {
+        Ok((value, effects)) => println!("Returned {:?} and asked for {} tells, moves, objects and timers", value, effects.len()),
+        Err(ScriptError::Failed(e)) => println!("Failed, {}", e),
+        Err(ScriptError::Stopped(e)) => println!("Stopped, {}", e)
     }
-    None
+    Ok(())
 }
 
-fn get_client_state_mut<'a>(v: &'a mut std::sync::MutexGuard<'_, dyn std::any::Any>) -> Option<&'a mut HashMap<String, String>> {
-    if let Some(val) = (*v.deref_mut()).downcast_mut::<HashMap<String, String>>() {
-        return Some(val);
+dyon_fn! {
+    fn test_func() {
     }
-    None
+}
+
+// The player's script state inside the object scripts get, None if it is something else
+pub fn with_client_state<T>(object: &RustObject, f: impl FnOnce(&mut HashMap<String, String>) -> T) -> Option<T> {
+    // A script stopped partway through can leave it poisoned, the map is still fine
+    let mut object = object.lock().unwrap_or_else(|e| e.into_inner());
+    object.downcast_mut::<HashMap<String, String>>().map(f)
 }
 
 dyon_fn! {
@@ -65,38 +98,190 @@ dyon_fn! {
 
 dyon_fn! {
     fn set_state(a: RustObject, key: String, value: String) {
-        let mut g: std::sync::MutexGuard<'_, dyn std::any::Any> = a.lock().unwrap();
-        let state = get_client_state_mut(&mut g);
-        if let Some(state) = state {
-            state.insert(key, value);
-        }
+        with_client_state(&a, |state| state.insert(key, value));
     }
 }
 
 dyon_fn! {
     fn get_state(a: RustObject, key: String) -> Option<String> {
-        let g: std::sync::MutexGuard<'_, dyn std::any::Any> = a.lock().unwrap();
-        let state = get_client_state(&g);
-        if let Some(state) = state {
-            return state.get(&key).map(|a| a.clone());
-        }
-        return None;
+        with_client_state(&a, |state| state.get(&key).cloned()).flatten()
+    }
+}
+
+dyon_fn! {
+    fn after(seconds: f64, function: String) -> f64 {
+        timers::request(seconds, TimerAction::Call(function)) as f64
+    }
+}
+
+dyon_fn! {
+    fn tell_after(seconds: f64, text: String) -> f64 {
+        timers::request(seconds, TimerAction::Message(text)) as f64
+    }
+}
+
+dyon_fn! {
+    fn cancel(handle: f64) {
+        timers::request_cancel(handle as u64);
+    }
+}
+
+dyon_fn! {
+    fn player_name() -> String {
+        script_api::with_context(|a| a.player.clone())
+    }
+}
+
+dyon_fn! {
+    fn player_stat(stat: String) -> f64 {
+        script_api::with_context(|a| a.stats.get(&stat).copied().unwrap_or(0) as f64)
+    }
+}
+
+dyon_fn! {
+    fn room_addr() -> String {
+        script_api::with_context(|a| a.room.clone())
+    }
+}
+
+dyon_fn! {
+    fn room_display() -> String {
+        script_api::with_context(|a| a.room_display.clone())
+    }
+}
+
+dyon_fn! {
+    fn room_objects() -> Vec<String> {
+        script_api::with_context(|a| a.room_objects.clone())
+    }
+}
+
+dyon_fn! {
+    fn room_links() -> Vec<String> {
+        script_api::with_context(|a| a.room_links.clone())
+    }
+}
+
+dyon_fn! {
+    fn tell(text: String) {
+        script_api::push(ScriptEffect::Tell(text));
+    }
+}
+
+dyon_fn! {
+    fn tell_room(text: String) {
+        script_api::push(ScriptEffect::TellRoom(text));
+    }
+}
+
+dyon_fn! {
+    fn move_player(addr: String) {
+        script_api::push(ScriptEffect::Move(addr));
     }
 }
 
-fn load_module(path: &String) -> std::io::Result<dyon::Module> {
+dyon_fn! {
+    fn add_object(name: String, display: String) {
+        script_api::push(ScriptEffect::AddObject { name, display });
+    }
+}
+
+dyon_fn! {
+    fn remove_object(name: String) {
+        script_api::push(ScriptEffect::RemoveObject(name));
+    }
+}
+
+// Appended to an upload that has a home fn, only type checks if home can be called the way RunScript calls it.
+// Named so it cannot clash with anything the upload already defines.
+fn home_check(module: &Module) -> String {
+    let mut name = String::from("check_home_entry_point");
+    while !matches!(module.find_function(&Arc::new(name.clone()), 0), FnIndex::None) {
+        name.push('_');
+    }
+    format!{"fn {}(state) -> str {{\n  return home(state)\n}}\n", name}
+}
+
+fn load_module(path: &str) -> std::io::Result<dyon::Module> {
+    let source = std::fs::read_to_string(path)?;
+    compile(path, source).map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))
+}
+
+// Compiles an upload without saving it, Err has Dyon's diagnostics or says what is wrong with home
+pub fn validate(name: &str, source: &str) -> Result<(), String> {
+    let module = compile(name, source.into())?;
+    if let FnIndex::None = module.find_function(&Arc::new("home".into()), 0) {
+        return Err("There is no fn home(state) -> str for RunScript to call.".into());
+    }
+    compile(name, format!{"{}\n{}", source, home_check(&module)})
+        .map(|_| ())
+        .map_err(|_| "home has to take one argument, the player's state, and return a str, like fn home(state) -> str.".into())
+}
+
+// Err is Dyon's diagnostic, with the line and column of the problem
+fn compile(name: &str, source: String) -> Result<Module, String> {
     let mut module = Module::new();
 
     let type_n = Type::AdHoc(Arc::new("StateObject".into()), Box::new(Type::Any)); 
     module.add_str("get_state", get_state, Dfn::nl(vec![type_n.clone(), Type::Str], Type::Option(Box::new(Type::Str))));
     module.add_str("set_state", set_state, Dfn::nl(vec![type_n.clone(), Type::Str, Type::Str], Type::Void));
     module.add_str("num", num, Dfn::nl(vec![Type::Str], Type::Option(Box::new(Type::F64))));
+    module.add_str("after", after, Dfn::nl(vec![Type::F64, Type::Str], Type::F64));
+    module.add_str("tell_after", tell_after, Dfn::nl(vec![Type::F64, Type::Str], Type::F64));
+    module.add_str("cancel", cancel, Dfn::nl(vec![Type::F64], Type::Void));
+    // Reads see the player and room as they were when the script started,
+    // everything else happens in order once it returns
+    module.add_str("player_name", player_name, Dfn::nl(vec![], Type::Str));
+    module.add_str("player_stat", player_stat, Dfn::nl(vec![Type::Str], Type::F64));
+    module.add_str("room_addr", room_addr, Dfn::nl(vec![], Type::Str));
+    module.add_str("room_display", room_display, Dfn::nl(vec![], Type::Str));
+    module.add_str("room_objects", room_objects, Dfn::nl(vec![], Type::Array(Box::new(Type::Str))));
+    module.add_str("room_links", room_links, Dfn::nl(vec![], Type::Array(Box::new(Type::Str))));
+    module.add_str("tell", tell, Dfn::nl(vec![Type::Str], Type::Void));
+    module.add_str("tell_room", tell_room, Dfn::nl(vec![Type::Str], Type::Void));
+    module.add_str("move_player", move_player, Dfn::nl(vec![Type::Str], Type::Void));
+    module.add_str("add_object", add_object, Dfn::nl(vec![Type::Str, Type::Str], Type::Void));
+    module.add_str("remove_object", remove_object, Dfn::nl(vec![Type::Str], Type::Void));
     
     module.add_str("test_func", test_func, Dfn::nl(vec![], Type::Void));
 
-    if error(load(path, &mut module)) {
-        return Err(std::io::Error::new(ErrorKind::InvalidData, "dyon script not valid or not found dyon/loader.dyon"));
-    }
+    // Dyon's checker panics on some things it was built without, like go, rather than erroring
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| load_str(name, Arc::new(source), &mut module)))
+        .unwrap_or_else(|_| Err(format!{"{} uses something scripts cannot, like go", name}))?;
 
     Ok(module)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uploads_can_use_any_fn_name() {
+        let source = "fn check_home_entry_point(state) -> str {\n  return \"mine\"\n}\nfn home(state) -> str {\n  return check_home_entry_point(state)\n}\n";
+        assert_eq!(validate("upload.dyon", source), Ok(()));
+    }
+
+    #[test]
+    fn home_has_to_be_callable_by_run_script() {
+        assert!(validate("upload.dyon", "fn main() {}\n").unwrap_err().contains("There is no fn home"));
+        assert!(validate("upload.dyon", "fn home(state) -> f64 {\n  return 1\n}\n").unwrap_err().contains("return a str"));
+    }
+
+    #[test]
+    fn edits_are_parsed_again_even_within_the_same_mtime() {
+        let path = std::env::temp_dir().join(format!{"module-cache-{}.dyon", std::process::id()});
+        let path_str = path.to_string_lossy().to_string();
+        std::fs::write(&path, "fn home(state) -> str {\n  return \"a\"\n}\n").unwrap();
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        let modules = ModuleCache::default();
+        let first = modules.get(&path_str).unwrap();
+        assert!(Arc::ptr_eq(&first, &modules.get(&path_str).unwrap()));
+
+        std::fs::write(&path, "fn home(state) -> str {\n  return \"abc\"\n}\n").unwrap();
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+        let second = modules.get(&path_str).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(!Arc::ptr_eq(&first, &second));
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+
+use serde_derive::{Serialize, Deserialize};
+
+use crate::{config::{self, ServerConfig}, states::GameObject};
+
+// SplitMix64, tiny and the same on every platform so a seed always rolls the same items
+pub struct ItemRng {
+    state: u64
+}
+
+impl ItemRng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // Inclusive on both ends
+    pub fn range(&mut self, low: i64, high: i64) -> i64 {
+        if high <= low {
+            return low;
+        }
+        // Wide enough that no pair of i64s overflows it
+        let span = (high as i128 - low as i128 + 1) as u128;
+        (low as i128 + (self.next_u64() as u128 % span) as i128) as i64
+    }
+
+    pub fn pick<'a>(&mut self, pool: &'a [String]) -> Option<&'a String> {
+        if pool.is_empty() {
+            return None;
+        }
+        pool.get(self.range(0, pool.len() as i64 - 1) as usize)
+    }
+}
+
+pub fn check_caps(difficulty: i64, level: i64, config: &ServerConfig) -> Result<(), String> {
+    config::check_cap("Difficulty", difficulty, 0, config.max_item_difficulty)?;
+    config::check_cap("Level", level, 0, config.max_item_level)
+}
+
+// Builders describe what an item could be, the server decides what it is when it drops
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ItemTemplate {
+    pub name: String,
+    pub names: Vec<String>,
+    pub descriptions: Vec<String>,
+    // The one stat every item has for now
+    pub stat: String,
+    pub difficulty: i64,
+    pub level: i64
+}
+
+impl ItemTemplate {
+    pub fn new(name: String, difficulty: i64, level: i64) -> Self {
+        Self {
+            name,
+            names: vec![],
+            descriptions: vec![],
+            stat: "power".into(),
+            difficulty,
+            level
+        }
+    }
+
+    // difficulty + level + rng(0, level / 5), saturating for templates saved before the caps
+    pub fn roll_stat(&self, rng: &mut ItemRng) -> i64 {
+        self.difficulty.saturating_add(self.level).saturating_add(rng.range(0, self.level / 5))
+    }
+
+    pub fn roll(&self, rng: &mut ItemRng) -> GameObject {
+        let name = rng.pick(&self.names).unwrap_or(&self.name).clone();
+        let description = rng.pick(&self.descriptions).cloned().unwrap_or_else(|| name.clone());
+        let value = self.roll_stat(rng);
+
+        let mut item = GameObject::new(name);
+        item.display = format!{"{} [{} {}]", description, self.stat, value};
+        item.portable = true;
+        item.stats = HashMap::from([(self.stat.clone(), value)]);
+        item
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{states::ServerState, storage::MemoryStore};
+
+    fn sword() -> ItemTemplate {
+        let mut template = ItemTemplate::new("sword".into(), 3, 20);
+        template.names = vec!["blade".into(), "sabre".into(), "cutlass".into()];
+        template.descriptions = vec!["A dull blade.".into(), "A shining blade.".into()];
+        template
+    }
+
+    fn rolls(rng: &mut ItemRng, count: usize) -> Vec<(String, i64)> {
+        (0..count).map(|_| {
+            let item = sword().roll(rng);
+            (item.display, item.stats["power"])
+        }).collect()
+    }
+
+    #[test]
+    fn a_seed_always_rolls_the_same_items() {
+        assert_eq!(rolls(&mut ItemRng::new(7), 50), rolls(&mut ItemRng::new(7), 50));
+        assert_ne!(rolls(&mut ItemRng::new(7), 50), rolls(&mut ItemRng::new(8), 50));
+    }
+
+    #[test]
+    fn stats_follow_the_formula() {
+        let mut rng = ItemRng::new(1);
+        for (_, power) in rolls(&mut rng, 500) {
+            assert!((23..=27).contains(&power), "{} is outside 3 + 20 + rng(0, 4)", power);
+        }
+    }
+
+    #[test]
+    fn huge_templates_do_not_overflow() {
+        let template = ItemTemplate::new("big".into(), i64::MAX, i64::MAX);
+        assert_eq!(template.roll_stat(&mut ItemRng::new(1)), i64::MAX);
+        ItemRng::new(1).range(i64::MIN, i64::MAX);
+    }
+
+    #[test]
+    fn caps_refuse_out_of_range_templates() {
+        let config = ServerConfig::default();
+        assert!(check_caps(config.max_item_difficulty, config.max_item_level, &config).is_ok());
+        assert!(check_caps(config.max_item_difficulty + 1, 0, &config).is_err());
+        assert!(check_caps(0, config.max_item_level + 1, &config).is_err());
+        assert!(check_caps(-1, 0, &config).is_err());
+    }
+
+    #[tokio::test]
+    async fn fights_do_not_change_drops() {
+        let seeded = || {
+            let config = ServerConfig { item_seed: Some(42), script_workers: 1, ..Default::default() };
+            let server_state = ServerState::new(config, Box::new(MemoryStore::default())).unwrap();
+            server_state.item_templates.lock().unwrap().insert("sword".into(), sword());
+            server_state
+        };
+        let quiet = seeded();
+        let busy = seeded();
+        for _ in 0..10 {
+            for _ in 0..7 {
+                busy.roll(1, 100);
+            }
+            assert_eq!(quiet.spawn_item("sword").unwrap().display, busy.spawn_item("sword").unwrap().display);
+        }
+    }
+}
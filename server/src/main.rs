@@ -1,16 +1,5 @@
-#![feature(async_closure)]
-
-mod dyon_inter;
-mod states;
-mod command_handlers;
-
-use std::{net::{SocketAddrV4, SocketAddr}, sync::Arc, string};
-use command_handlers::{handle_touch, look, add_object, describe_object, add_action, upload_script, add_link, login};
-use lazy_static::lazy_static;
-use states::{ServerState, ClientState, ClientPointer};
-use tokio::{net::{TcpListener, TcpStream}, io::{BufReader, AsyncBufReadExt, AsyncWriteExt}};
-
-use crate::command_handlers::move_into;
+// Tests do not go through main, so most of the server looks unused to them
+#![cfg_attr(test, allow(dead_code))]
 
 macro_rules! escaped {
     ($exp:expr) => {
@@ -18,121 +7,234 @@ macro_rules! escaped {
     }
 }
 
-async fn process_builder_command(input: String, _addr: SocketAddr, server_state: Arc<ServerState>, my_client: ClientPointer) -> String {
-    match &input[..input.find(" ").unwrap_or(input.len())] {
-        "\\script" => {
-            return upload_script(&input).await;
-        },
-        "\\add" => {
-            return add_object(&input, server_state, my_client).await;
-        },
-        "\\link" => {
-            return add_link(&input, server_state, my_client).await;
-        },
-        "\\describe" => {
-            return describe_object(&input, server_state, my_client).await;
-        },
-        "\\action" => {
-            return add_action(&input, server_state, my_client).await;
-        },
-        "\\save" => {
-            server_state.save().expect("Failed to save server!!");
-            server_state.save_client(my_client.clone()).await.expect("Failed to save client!!");
-            return format!{"Nice save!"};
-        },
-        _ => {}
-    }
-
-    "Nice builder command.".into()
-}
+#[macro_use]
+mod args;
+#[macro_use]
+mod tick;
+mod accounts;
+mod bench;
+mod channels;
+mod combat;
+mod dyon_inter;
+mod items;
+mod npcs;
+mod offline;
+mod sandbox;
+mod script_api;
+mod skills;
+mod states;
+mod command_handlers;
+mod commands;
+mod config;
+mod storage;
+mod timers;
 
-async fn process_client_command(input: String, addr: SocketAddr, server_state: Arc<ServerState>, my_client: ClientPointer) -> String {
+use std::{net::{SocketAddrV4, SocketAddr}, sync::Arc, time::{Duration, Instant}};
+use commands::COMMANDS;
+use lazy_static::lazy_static;
+use config::ServerConfig;
+use states::{ServerState, ClientState, ClientPointer, Role, unix_now};
+use tick::{Scheduler, TickFuture};
+use tokio::{net::{TcpListener, TcpStream, tcp::OwnedWriteHalf}, io::{BufReader, AsyncBufReadExt, AsyncWriteExt}, sync::mpsc};
+
+async fn process_client_command(input: String, _addr: SocketAddr, server_state: Arc<ServerState>, my_client: ClientPointer) -> String {
     //Manage server game state here
+    COMMANDS.dispatch(input, server_state, my_client).await
+}
 
-    if input.starts_with("\\") {
-        return process_builder_command(input, addr, server_state, my_client).await;
-    }
-
-    let temp_usize = input.find(" ").unwrap_or(input.len());
-    match &input[..temp_usize] {
-        "i" => {
-            return handle_touch(&input, server_state, my_client).await;
-        },
-        "look" => {
-            return look(&input, server_state, my_client).await;
-        },
-        "move" => {
-            return move_into(&input, server_state, my_client).await;
-        },
-        "login" => {
-            return login(&input, server_state, my_client).await;
-        }
-        "help" => {
-            return format!{
-                "{}\n{}\n{}\n{}",
-                "i - interacts with object: i {object} {action}",
-                "look - reads the object's display text: look {object}",
-                "move - move into a different area: move {area name}",
-                "help - you're here"
-            };
+// Owns the socket's write half, everything a player sees goes through their outbox to here
+async fn write_outbox(mut write: OwnedWriteHalf, mut inbox: mpsc::UnboundedReceiver<String>) {
+    while let Some(message) = inbox.recv().await {
+        if write.write_all(escaped!{message}).await.is_err() {
+            break;
         }
-        _ => {}
     }
-
-    return String::from("The room is quiet.");
 }
 
-async fn process(mut _socket: TcpStream, addr: SocketAddr, server_state: Arc<ServerState>) {
-    let (read, mut write) = _socket.split();
+async fn process(socket: TcpStream, addr: SocketAddr, server_state: Arc<ServerState>) {
+    let (read, write) = socket.into_split();
     let mut reader = BufReader::new(read);
-    let client_state = ClientState::new(Some(addr)).to_pointer();
+    let (outbox, inbox) = mpsc::unbounded_channel::<String>();
+    let writer = tokio::spawn(write_outbox(write, inbox));
+
+    let mut client_state = ClientState::new(Some(addr));
+    client_state.outbox = Some(outbox.clone());
+    let client_state = client_state.into_pointer();
     server_state.client_states.lock().await.push(
         client_state.clone()
     );
-    //write.write(escaped!("\x1B[2J")).await.unwrap();
-    write.write(escaped!("@DWelcome to the server.")).await.unwrap();
-    write.write(escaped!("@DPlease enter your name (no spaces, or you'll be doomed).")).await.unwrap();
-
-    let mut string_input = String::new();
-    reader.read_line(&mut string_input).await.expect("Read username error");
-    let string_input = string_input.replace(|a| a == '\r' || a == '\n', "");
-    // Login if that account exists.
-    let new_client = server_state.load_client(string_input.clone()).await;
+    //outbox.send("\x1B[2J".into());
+    let _ = outbox.send("@DWelcome to the server.".into());
+
+    let new_client = accounts::authenticate(&mut reader, &outbox, &server_state).await;
+    let mut shutting_down = false;
     if let Some(mut new_client) = new_client {
         new_client.addr = Some(addr);
-        new_client.name = string_input;
+        new_client.outbox = Some(outbox.clone());
+        if server_state.config.admins.contains(&new_client.name) {
+            new_client.role = Role::Admin;
+        }
+        new_client.last_seen = unix_now();
+        // Only now they have proved who they are, it may run their offline script
+        let report = offline::apply(&server_state, &mut new_client).await;
         *client_state.lock().await = new_client;
-        write.write(escaped!{"Welcome back :)"}).await.unwrap();
+        // Save straight away so the name and password are claimed
+        if let Err(e) = server_state.save_client(client_state.clone()).await {
+            eprintln!("Failed to save {}: {}", addr, e);
+        }
+        server_state.arrive(&client_state).await;
+        if let Some(report) = report {
+            let _ = outbox.send(report);
+        }
+
+        let mut shutdown = server_state.shutdown.subscribe();
+        loop {
+            let mut string_input = String::new();
+            tokio::select! {
+                read = reader.read_line(&mut string_input) => {
+                    // 0 bytes means the client hung up
+                    if read.map(|a| a == 0).unwrap_or(true) {
+                        break;
+                    }
+                },
+                _ = shutdown.changed() => {
+                    // main has already told everyone and saves them once we let go
+                    shutting_down = true;
+                    break;
+                }
+            }
+            let string_input = string_input.replace(['\r', '\n'], "");
+            client_state.lock().await.last_input = Instant::now();
+            // Whatever follows it, quit now or a stray space, they still mean to go
+            if string_input.split_whitespace().next() == Some("quit") {
+                break;
+            }
+            let response = process_client_command(string_input.clone(), addr, server_state.clone(), client_state.clone()).await;
+            let _ = outbox.send(response);
+        }
+        if !shutting_down {
+            server_state.depart(&client_state).await;
+            client_state.lock().await.logged_out();
+        }
     } else {
-        write.write(escaped!{"New face... Don't forget to save :)"}).await.unwrap();
-        client_state.lock().await.name = string_input;
+        let _ = outbox.send("@BGoodbye.".into());
     }
+
+    // On shutdown main still needs them listed to save them
+    if !shutting_down {
+        server_state.client_states.lock().await.retain(|a| !Arc::ptr_eq(a, &client_state));
+        if let Err(e) = server_state.save_client_if_dirty(client_state.clone()).await {
+            eprintln!("Failed to save {}: {}", addr, e);
+        }
+        // Only once they are saved, so whoever logs in as them next loads what they left with
+        let name = client_state.lock().await.name.clone();
+        server_state.release_name(&name);
+    }
+    // The writer stops once every sender is gone, give it a moment to flush the last lines
+    client_state.lock().await.outbox = None;
+    drop(outbox);
+    let _ = tokio::time::timeout(Duration::from_secs(2), writer).await;
+}
+
+async fn autosave(server_state: Arc<ServerState>, every: Duration) {
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + every, every);
     loop {
-        let mut string_input = String::new();
-        reader.read_line(&mut string_input).await.expect("Read error");
-        let string_input = string_input.replace(|a| a == '\r' || a == '\n', "");
-        if string_input == "quit" {
-            server_state.client_states.lock().await.retain(|a| a.blocking_lock().addr != Some(addr));
-            break;
+        interval.tick().await;
+        match server_state.save_all(true).await {
+            Ok((0, 0)) => {},
+            Ok((rooms, players)) => println!("Autosaved {} rooms and {} players", rooms, players),
+            Err(e) => eprintln!("Autosave failed: {}", e)
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {}
         }
-        let response = process_client_command(string_input.clone(), addr, server_state.clone(), client_state.clone()).await;
-        write.write(escaped! {response}).await.expect("Write error");
     }
-    server_state.client_states.lock().await.retain(|a| a.blocking_lock().addr != Some(addr));
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.expect("Failed to listen for ctrl-c");
+}
+
+fn main() -> std::io::Result<()> {
+    // Script workers are their own processes, started by the sandbox, and never need a runtime
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|a| a.as_str()) == Some("script-worker") {
+        return sandbox::run_worker(&args[2..]);
+    }
+    serve()
 }
 
 #[tokio::main]
-async fn main() -> std::io::Result<()> {
+async fn serve() -> std::io::Result<()> {
+    if std::env::args().nth(1).as_deref() == Some("bench-scripts") {
+        let actions = std::env::args().nth(2).and_then(|a| a.parse().ok()).unwrap_or(200);
+        let workers = std::env::args().nth(3).and_then(|a| a.parse().ok()).unwrap_or(0);
+        return bench::run(actions, workers).await;
+    }
+    if std::env::args().nth(1).as_deref() == Some("run-script") {
+        let path = std::env::args().nth(2).ok_or_else(|| std::io::Error::other("usage: server run-script <path> [function]"))?;
+        let function = std::env::args().nth(3).unwrap_or_else(|| "home".into());
+        return dyon_inter::run_once(&path, &function).await;
+    }
+
     let addr: SocketAddrV4 = "127.0.0.1:8080".parse().unwrap();
     let server = TcpListener::bind(addr).await?;
 
-    let server_state = Arc::new(ServerState::new());
+    let config = ServerConfig::load("config.json")?;
+    let store = storage::open_store(&config)?;
+    let server_state = Arc::new(ServerState::new(config.clone(), store)?);
     //dyon_inter::load_and_run(&"dyon/test.dyon".into(), &server_state.runtime).await?;
+    if config.autosave_secs > 0 {
+        tokio::spawn(autosave(server_state.clone(), Duration::from_secs(config.autosave_secs)));
+    }
+
+    let mut ticks = Scheduler::new(Duration::from_millis(config.tick_ms));
+    if config.npc_tick_secs > 0 {
+        ticks.subscribe("npc respawns", Duration::from_secs(config.npc_tick_secs), tick_handler!(npcs::respawn));
+        ticks.subscribe("npc behaviour", Duration::from_secs(config.npc_tick_secs), tick_handler!(npcs::behave));
+    }
+    if config.regen_secs > 0 {
+        ticks.subscribe("regeneration", Duration::from_secs(config.regen_secs), tick_handler!(combat::regenerate));
+    }
+    ticks.subscribe("script timers", Duration::from_secs(1), tick_handler!(timers::fire));
+    tokio::spawn(ticks.run(server_state.clone()));
+
+    // Every connection holds a clone, recv() returns None once they have all finished
+    let (connections_open, mut connections_closed) = mpsc::channel::<()>(1);
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
     loop {
         let server_state = server_state.clone();
-        let (socket, addr) = server.accept().await?;
-        tokio::spawn(async move {
-            process(socket, addr, server_state).await;
-        });
+        let connection = connections_open.clone();
+        tokio::select! {
+            accepted = server.accept() => {
+                let (socket, addr) = accepted?;
+                tokio::spawn(async move {
+                    process(socket, addr, server_state).await;
+                    drop(connection);
+                });
+            },
+            _ = &mut shutdown => break
+        }
+    }
+
+    println!("Shutting down...");
+    drop(server);
+    server_state.broadcast("@BThe server is shutting down, your progress is being saved.".into(), None).await;
+    let _ = server_state.shutdown.send(true);
+    for client in server_state.client_states.lock().await.iter() {
+        client.lock().await.logged_out();
     }
+    let (rooms, players) = server_state.save_all(false).await?;
+    println!("Saved {} rooms and {} players", rooms, players);
+    drop(connections_open);
+    let _ = tokio::time::timeout(Duration::from_secs(5), connections_closed.recv()).await;
+    Ok(())
 }
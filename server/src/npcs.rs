@@ -0,0 +1,238 @@
+use std::{sync::Arc, time::{Duration, Instant}};
+
+use dyon::Variable;
+use serde_derive::{Serialize, Deserialize};
+
+use crate::{combat, config::ServerConfig, dyon_inter, script_api::ScriptContext, skills::{self, SkillRequirement}, states::{ClientPointer, RoomAddr, RoomPointer, ServerState}};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Loot {
+    pub template: String,
+    // Percent chance to drop on each kill
+    pub chance: i64
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Npc {
+    pub name: String,
+    pub display: String,
+    pub level: i64,
+    pub health: i64,
+    pub max_health: i64,
+    // Most it hits for, each hit rolls 1 to this
+    pub damage: i64,
+    pub loot: Vec<Loot>,
+    // dyon/<script>.dyon with fn behave(name: str, health: f64, players: f64) -> str,
+    // returning "attack" to attack someone, other text to emote it, or "" to idle
+    pub script: Option<String>
+}
+
+impl Npc {
+    pub fn new(name: String, level: i64, max_health: i64, damage: i64) -> Self {
+        Self {
+            display: format!{"A {}.", name},
+            name,
+            level,
+            health: max_health,
+            max_health,
+            damage,
+            loot: vec![],
+            script: None
+        }
+    }
+
+    // Kills teach combat like an action would, with its damage as the difficulty
+    pub fn requirement(&self, config: &ServerConfig) -> SkillRequirement {
+        SkillRequirement {
+            skill: combat::COMBAT_SKILL.into(),
+            difficulty: self.damage.clamp(1, config.max_action_difficulty),
+            level: self.level
+        }
+    }
+}
+
+// Keeps up to max copies of the npc in a room, replacing each one respawn_secs after it dies
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SpawnRule {
+    pub npc: Npc,
+    pub max: usize,
+    pub respawn_secs: u64,
+    // When the dead ones come back, only meaningful while the server is up
+    #[serde(skip)]
+    pub respawn_at: Vec<Instant>
+}
+
+impl SpawnRule {
+    pub fn new(npc: Npc) -> Self {
+        Self { npc, max: 1, respawn_secs: 60, respawn_at: vec![] }
+    }
+}
+
+// Spawns whatever is due in one room, returns the names of what appeared
+fn spawn_due(room: &mut crate::states::Room) -> Vec<String> {
+    let now = Instant::now();
+    let mut spawned = vec![];
+    for rule in room.spawns.iter_mut() {
+        rule.respawn_at.retain(|a| *a > now);
+        let alive = room.npcs.iter().filter(|a| a.name == rule.npc.name).count();
+        // Anything neither alive nor waiting to respawn comes in now, which fills rooms at startup
+        for _ in (alive + rule.respawn_at.len())..rule.max {
+            room.npcs.push(rule.npc.clone());
+            spawned.push(rule.npc.name.clone());
+        }
+    }
+    spawned
+}
+
+async fn run_behaviour(server_state: &ServerState, script: &str, npc: &Npc, addr: &RoomAddr, players: usize) -> std::io::Result<String> {
+    let args = vec![Variable::Str(Arc::new(npc.name.clone())), Variable::f64(npc.health as f64), Variable::f64(players as f64)];
+    let label = format!{"the {} in {}", npc.name, addr};
+    let (result, _) = dyon_inter::call(server_state, &format!{"dyon/{}.dyon", script}, "behave", args, ScriptContext::default(), &label).await
+        .map_err(std::io::Error::other)?;
+    match result {
+        Some(Variable::Str(text)) => Ok((*text).clone()),
+        _ => Err(std::io::Error::other("behave did not return a str"))
+    }
+}
+
+// The npc's half of a fight, nothing happens if it is already gone
+async fn npc_attacks(server_state: &Arc<ServerState>, addr: &RoomAddr, room: &RoomPointer, npc_name: &str, target: &ClientPointer) {
+    let damage = {
+        let room_ref = room.lock().await;
+        match room_ref.npcs.iter().find(|a| a.name == npc_name) {
+            Some(npc) => server_state.roll(1, npc.damage.max(1)),
+            None => return
+        }
+    };
+    let (target_name, health, max_health) = {
+        let mut target_ref = target.lock().await;
+        target_ref.health -= damage;
+        (target_ref.name.clone(), target_ref.health.max(0), target_ref.max_health)
+    };
+    server_state.send_to_room(addr, format!{"@BThe {} hits {} for {} ({}/{}).", npc_name, target_name, damage, health, max_health}, None).await;
+    if health <= 0 {
+        server_state.send_to_room(addr, format!{"@B{} has been slain by the {}!", target_name, npc_name}, None).await;
+        combat::respawn(server_state, target).await;
+    }
+}
+
+// One swing at an npc in the attacker's room, it hits back if it lives
+pub async fn attack_npc(server_state: &Arc<ServerState>, attacker: &ClientPointer, addr: &RoomAddr, npc_name: &str) -> Option<String> {
+    let room = server_state.get_room(addr).await?;
+    if !room.lock().await.npcs.iter().any(|a| a.name == npc_name) {
+        return None;
+    }
+
+    let (name, damage, weapon) = {
+        let mut attacker_ref = attacker.lock().await;
+        if let Err(e) = combat::ready(server_state, &mut attacker_ref) {
+            return Some(e);
+        }
+        (attacker_ref.name.clone(), combat::damage(server_state, &attacker_ref), combat::weapon_name(&attacker_ref))
+    };
+
+    // Some(npc) if this killed it
+    let (health, max_health, killed) = {
+        let mut room_ref = room.lock().await;
+        let index = room_ref.npcs.iter().position(|a| a.name == npc_name)?;
+        room_ref.npcs[index].health -= damage;
+        let npc = room_ref.npcs[index].clone();
+        if npc.health > 0 {
+            (npc.health, npc.max_health, None)
+        } else {
+            room_ref.npcs.remove(index);
+            if let Some(rule) = room_ref.spawns.iter_mut().find(|a| a.npc.name == npc_name) {
+                // Capped again for rules saved before \npc_spawn checked it
+                let wait = Duration::from_secs(rule.respawn_secs.min(server_state.config.max_npc_respawn_secs));
+                if let Some(at) = Instant::now().checked_add(wait) {
+                    rule.respawn_at.push(at);
+                }
+            }
+            (0, npc.max_health, Some(npc))
+        }
+    };
+    let hit = format!{"@B{} hits the {} with {} for {} ({}/{}).", name, npc_name, weapon, damage, health, max_health};
+    server_state.send_to_room(addr, hit.clone(), Some(attacker)).await;
+
+    let npc = match killed {
+        Some(npc) => npc,
+        None => {
+            // Push ours first so the counter lands after it
+            attacker.lock().await.send(hit);
+            npc_attacks(server_state, addr, &room, npc_name, attacker).await;
+            return Some(format!{"The {} fights on.", npc_name});
+        }
+    };
+
+    server_state.send_to_room(addr, format!{"@BThe {} has been slain by {}!", npc_name, name}, Some(attacker)).await;
+    let mut lines = vec![hit, format!{"You have slain the {}!", npc_name}];
+    let mut attacker_ref = attacker.lock().await;
+    for loot in npc.loot.iter() {
+        if server_state.roll(1, 100) > loot.chance {
+            continue;
+        }
+        if let Some(item) = server_state.spawn_item(&loot.template) {
+            lines.push(format!{"@CIt dropped a {}, you take it.", item.name});
+            attacker_ref.inventory.push(item);
+        }
+    }
+    let requirement = npc.requirement(&server_state.config);
+    let xp = skills::xp_reward(&requirement, attacker_ref.skill_level(&requirement.skill), &server_state.config);
+    if xp > 0 {
+        lines.push(match attacker_ref.add_xp(&requirement.skill, xp) {
+            Some(level) => format!{"@C+{} combat xp. You reached level {} combat!", xp, level},
+            None => format!{"@C+{} combat xp.", xp}
+        });
+    }
+    Some(lines.join("\n"))
+}
+
+async fn all_rooms(server_state: &ServerState) -> Vec<(RoomAddr, RoomPointer)> {
+    server_state.rooms.lock().await.iter().map(|(a, b)| (a.clone(), b.clone())).collect()
+}
+
+// Brings back whatever is due in every room
+pub async fn respawn(server_state: &Arc<ServerState>) {
+    for (addr, room) in all_rooms(server_state).await {
+        let spawned = spawn_due(&mut *room.lock().await);
+        for name in spawned {
+            server_state.send_to_room(&addr, format!{"@CA {} appears.", name}, None).await;
+        }
+    }
+}
+
+// Lets every scripted npc act
+pub async fn behave(server_state: &Arc<ServerState>) {
+    for (addr, room) in all_rooms(server_state).await {
+        let scripted: Vec<Npc> = room.lock().await.npcs.iter().filter(|a| a.script.is_some()).cloned().collect();
+        if scripted.is_empty() {
+            continue;
+        }
+
+        let mut players = vec![];
+        for client in server_state.clients_in_room(&addr).await {
+            if !server_state.is_hidden(&*client.lock().await) {
+                players.push(client);
+            }
+        }
+        for npc in scripted {
+            let script = npc.script.clone().unwrap();
+            let action = match run_behaviour(server_state, &script, &npc, &addr, players.len()).await {
+                Ok(action) => action,
+                Err(e) => {
+                    eprintln!("Behaviour script {} failed for {} in {}: {}", script, npc.name, addr, e);
+                    continue;
+                }
+            };
+            match action.as_str() {
+                "" => {},
+                "attack" if !players.is_empty() => {
+                    let target = &players[server_state.roll(0, players.len() as i64 - 1) as usize];
+                    npc_attacks(server_state, &addr, &room, &npc.name, target).await;
+                },
+                "attack" => {},
+                text => server_state.send_to_room(&addr, format!{"@CThe {} {}", npc.name, text}, None).await
+            }
+        }
+    }
+}
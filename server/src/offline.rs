@@ -0,0 +1,153 @@
+use std::sync::Arc;
+
+use dyon::Variable;
+
+use crate::{dyon_inter, script_api::ScriptContext, states::{ClientState, ServerState, unix_now}};
+
+// What a player earned while logged out, before the caps
+struct Rewards {
+    xp: i64,
+    resources: i64
+}
+
+// xp_per_hour and resources_per_hour for every hour away, up to offline_max_hours
+fn formula(server_state: &ServerState, seconds: u64) -> Rewards {
+    let config = &server_state.config;
+    Rewards {
+        xp: config.offline_xp_per_hour * seconds as i64 / 3600,
+        resources: config.offline_resources_per_hour * seconds as i64 / 3600
+    }
+}
+
+// dyon/<offline_script>.dyon can replace the formula with
+// fn offline(skill: str, level: f64, seconds: f64) -> {} returning {xp: f64, resources: f64}
+async fn run_hook(server_state: &ServerState, script: &str, player: &str, skill: &str, level: i64, seconds: u64) -> std::io::Result<Rewards> {
+    let args = vec![Variable::Str(Arc::new(skill.into())), Variable::f64(level as f64), Variable::f64(seconds as f64)];
+    let label = format!{"offline progress for {}", player};
+    let (result, _) = dyon_inter::call(server_state, &format!{"dyon/{}.dyon", script}, "offline", args, ScriptContext::default(), &label).await
+        .map_err(std::io::Error::other)?;
+    let fields = match result {
+        Some(Variable::Object(object)) => object,
+        _ => return Err(std::io::Error::other("offline did not return an object"))
+    };
+    let field = |name: &str| match fields.get(&Arc::new(name.to_string())) {
+        Some(Variable::F64(value, _)) => *value as i64,
+        _ => 0
+    };
+    Ok(Rewards { xp: field("xp"), resources: field("resources") })
+}
+
+// Credits the time since they last logged out to their idle activity, call once they are logged in.
+// Returns the "While you were away" summary if they earned anything.
+pub async fn apply(server_state: &ServerState, client: &mut ClientState) -> Option<String> {
+    let config = &server_state.config;
+    let now = unix_now();
+    // Zero while they play and set again only by a real logout, so time online is never
+    // credited, not even when the server dies before they leave
+    let last_logout = std::mem::take(&mut client.last_logout);
+    let skill = client.idle_activity.clone()?;
+    if last_logout == 0 {
+        return None;
+    }
+
+    let away = now.saturating_sub(last_logout);
+    if away < config.offline_min_minutes * 60 {
+        return None;
+    }
+    let seconds = away.min(config.offline_max_hours * 3600);
+    let level = client.skill_level(&skill);
+
+    // Whatever the hook says, never more than the formula allows for the longest absence
+    let most = formula(server_state, config.offline_max_hours * 3600);
+    let rewards = match &config.offline_script {
+        Some(script) => match run_hook(server_state, script, &client.name, &skill, level, seconds).await {
+            Ok(rewards) => rewards,
+            Err(e) => {
+                eprintln!("Offline script {} failed for {}: {}", script, client.name, e);
+                formula(server_state, seconds)
+            }
+        },
+        None => formula(server_state, seconds)
+    };
+    let xp = rewards.xp.clamp(0, most.xp);
+    let resources = rewards.resources.clamp(0, most.resources);
+    if xp == 0 && resources == 0 {
+        return None;
+    }
+
+    let mut report = format!{"@CWhile you were away for {}h {}m you practised {}", away / 3600, away % 3600 / 60, skill};
+    if away > seconds {
+        report = format!{"{} (only the first {} hours count)", report, config.offline_max_hours};
+    }
+    report = format!{"{}:\n+{} {} xp, +{} {}", report, xp, skill, resources, config.offline_resource};
+    if let Some(level) = client.add_xp(&skill, xp) {
+        report = format!{"{}\nYou reached level {} {}!", report, level, skill};
+    }
+    *client.resources.entry(config.offline_resource.clone()).or_insert(0) += resources;
+    Some(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::ServerConfig, storage::MemoryStore};
+
+    fn server() -> ServerState {
+        let config = ServerConfig {
+            offline_min_minutes: 5,
+            offline_max_hours: 12,
+            offline_xp_per_hour: 60,
+            offline_resources_per_hour: 20,
+            script_workers: 1,
+            ..Default::default()
+        };
+        ServerState::new(config, Box::new(MemoryStore::default())).unwrap()
+    }
+
+    fn away_for(seconds: u64) -> ClientState {
+        let mut client = ClientState::new(None);
+        client.idle_activity = Some("mining".into());
+        client.last_logout = unix_now() - seconds;
+        client
+    }
+
+    #[tokio::test]
+    async fn short_absences_earn_nothing() {
+        let server_state = server();
+        let mut client = away_for(4 * 60);
+        assert_eq!(apply(&server_state, &mut client).await, None);
+        assert_eq!(client.last_logout, 0);
+        assert!(client.skills.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rewards_follow_the_hourly_rates() {
+        let server_state = server();
+        let mut client = away_for(2 * 3600);
+        let report = apply(&server_state, &mut client).await.unwrap();
+        assert!(report.contains("+120 mining xp, +40 coins"), "{}", report);
+        assert_eq!(client.skills["mining"], 120);
+        assert_eq!(client.resources["coins"], 40);
+    }
+
+    #[tokio::test]
+    async fn only_the_first_max_hours_count() {
+        let server_state = server();
+        let mut client = away_for(3 * 24 * 3600);
+        let report = apply(&server_state, &mut client).await.unwrap();
+        assert!(report.contains("only the first 12 hours count"), "{}", report);
+        assert_eq!(client.skills["mining"], 12 * 60);
+        assert_eq!(client.resources["coins"], 12 * 20);
+    }
+
+    #[tokio::test]
+    async fn nothing_without_an_idle_activity_or_a_logout() {
+        let server_state = server();
+        let mut client = away_for(3600);
+        client.idle_activity = None;
+        assert_eq!(apply(&server_state, &mut client).await, None);
+        let mut client = away_for(3600);
+        client.last_logout = 0;
+        assert_eq!(apply(&server_state, &mut client).await, None);
+    }
+}
@@ -0,0 +1,474 @@
+use std::{cell::Cell, collections::HashMap, fmt, io::{self, BufRead, Write}, panic::{self, AssertUnwindSafe}, process::Stdio, sync::Arc, thread, time::Duration};
+
+use dyon::{Module, Runtime, RustObject, Variable, ast};
+use serde_derive::{Serialize, Deserialize};
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines}, process::{Child, ChildStdin, ChildStdout, Command}, sync::{Semaphore, SemaphorePermit}};
+
+use crate::{config::ServerConfig, dyon_inter::{self, ModuleCache}, script_api::{self, ScriptContext, ScriptEffect}, timers};
+
+// Scripts run on a thread with a big stack, one that recurses through all of it takes its worker down
+const STACK_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Clone, Copy)]
+pub struct Limits {
+    pub time: Duration,
+    pub memory: usize,
+    pub effects: usize,
+    pub timer_secs: u64
+}
+
+impl Limits {
+    pub fn new(config: &ServerConfig) -> Self {
+        Self {
+            time: Duration::from_millis(config.script_time_ms),
+            memory: config.script_memory_kb * 1024,
+            effects: config.script_max_effects,
+            timer_secs: config.script_max_timer_secs
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ScriptError {
+    // Missing, would not parse or failed while running
+    Failed(String),
+    // Broke one of its limits and was stopped
+    Stopped(String)
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Failed(e) | Self::Stopped(e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+impl From<std::io::Error> for ScriptError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Failed(e.to_string())
+    }
+}
+
+pub type ScriptResult = Result<(Option<Variable>, Vec<ScriptEffect>), ScriptError>;
+
+// Dyon values on their way to and from a worker. Numbers go as bits so NaN and infinity survive JSON,
+// anything else a script can make, like a closure, comes back as none.
+#[derive(Serialize, Deserialize)]
+enum Value {
+    Str(String),
+    Number(u64),
+    Bool(bool),
+    List(Vec<Value>),
+    Object(HashMap<String, Value>),
+    Maybe(Option<Box<Value>>),
+    // The player's script state, what get_state and set_state work on
+    State(HashMap<String, String>)
+}
+
+impl Value {
+    fn from_variable(variable: &Variable) -> Self {
+        match variable {
+            Variable::Str(a) => Self::Str((**a).clone()),
+            Variable::F64(a, _) => Self::Number(a.to_bits()),
+            Variable::Bool(a, _) => Self::Bool(*a),
+            Variable::Array(a) => Self::List(a.iter().map(Self::from_variable).collect()),
+            Variable::Object(a) => Self::Object(a.iter().map(|(k, v)| ((**k).clone(), Self::from_variable(v))).collect()),
+            Variable::Option(a) => Self::Maybe(a.as_ref().map(|a| Box::new(Self::from_variable(a)))),
+            Variable::RustObject(a) => Self::State(script_state(a)),
+            _ => Self::Maybe(None)
+        }
+    }
+
+    // Dyon's arrays and objects are Arcs whatever clippy thinks of them
+    #[allow(clippy::arc_with_non_send_sync)]
+    fn into_variable(self) -> Variable {
+        match self {
+            Self::Str(a) => Variable::Str(Arc::new(a)),
+            Self::Number(a) => Variable::f64(f64::from_bits(a)),
+            Self::Bool(a) => Variable::bool(a),
+            Self::List(a) => Variable::Array(Arc::new(a.into_iter().map(Self::into_variable).collect())),
+            Self::Object(a) => Variable::Object(Arc::new(a.into_iter().map(|(k, v)| (Arc::new(k), v.into_variable())).collect())),
+            Self::Maybe(a) => Variable::Option(a.map(|a| Box::new(a.into_variable()))),
+            Self::State(a) => Variable::RustObject(Arc::new(std::sync::Mutex::new(a)))
+        }
+    }
+}
+
+fn script_state(object: &RustObject) -> HashMap<String, String> {
+    dyon_inter::with_client_state(object, |state| state.clone()).unwrap_or_default()
+}
+
+#[derive(Serialize, Deserialize)]
+struct Call {
+    path: String,
+    function: String,
+    args: Vec<Value>,
+    context: ScriptContext,
+    // Timer handles the script can hand out, the server reserves them so workers never reuse one
+    first_handle: u64
+}
+
+// Parsing builder source is as much a risk as running it, so that happens in a worker too
+#[derive(Serialize, Deserialize)]
+enum Job {
+    Call(Call),
+    // An upload that is not saved yet, see dyon_inter::validate
+    Validate { path: String, source: String },
+    // Parses the script at path again, the worker keeps it for its next call
+    Load { path: String }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Reply {
+    result: Result<Option<Value>, ScriptError>,
+    effects: Vec<ScriptEffect>,
+    // Script state the run set, only what changed so runs for the same player do not undo each other
+    state: HashMap<String, String>,
+    // The worker holds on to more than one script's worth of memory and quits after this reply
+    retire: bool
+}
+
+// Unwound out of the interpreter once a script asks for too much
+struct TooManyEffects;
+
+thread_local! {
+    static EFFECTS: Cell<usize> = const { Cell::new(0) };
+    // Only set in a worker while it runs a script
+    static MAX_EFFECTS: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+// Called from script api fns for everything a script asks the server to do
+pub fn count_effect() {
+    let max = match MAX_EFFECTS.get() {
+        Some(max) => max,
+        None => return
+    };
+    EFFECTS.set(EFFECTS.get() + 1);
+    if EFFECTS.get() > max {
+        panic::panic_any(TooManyEffects);
+    }
+}
+
+// Bytes of address space the worker has mapped
+#[cfg(target_os = "linux")]
+fn address_space() -> Option<usize> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages = statm.split_whitespace().next()?.parse::<usize>().ok()?;
+    Some(pages * unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn address_space() -> Option<usize> {
+    None
+}
+
+// Caps the worker at what it has mapped now plus memory bytes, None lifts the cap. Past it
+// allocations fail and the worker aborts, which the server reports as using too much memory.
+#[cfg(target_os = "linux")]
+fn limit_memory(memory: Option<usize>) {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(libc::RLIMIT_AS, &mut limit) } != 0 {
+        return;
+    }
+    limit.rlim_cur = match memory.zip(address_space()) {
+        Some((memory, mapped)) => ((mapped + memory) as libc::rlim_t).min(limit.rlim_max),
+        None => limit.rlim_max
+    };
+    unsafe { libc::setrlimit(libc::RLIMIT_AS, &limit) };
+}
+
+// Elsewhere only time and effects are limited
+#[cfg(not(target_os = "linux"))]
+fn limit_memory(_memory: Option<usize>) {}
+
+// Replies get their own copy of stdout, stdout itself goes to stderr so what scripts print ends up in the log
+#[cfg(unix)]
+fn take_stdout() -> io::Result<Box<dyn Write + Send>> {
+    use std::os::fd::FromRawFd;
+    let replies = unsafe { libc::dup(1) };
+    if replies < 0 || unsafe { libc::dup2(2, 1) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Box::new(unsafe { std::fs::File::from_raw_fd(replies) }))
+}
+
+#[cfg(not(unix))]
+fn take_stdout() -> io::Result<Box<dyn Write + Send>> {
+    Ok(Box::new(io::stdout()))
+}
+
+fn call(runtime: &mut Runtime, module: &Arc<Module>, function: &str, args: Vec<Variable>) -> Result<Option<Variable>, String> {
+    let call = ast::Call {
+        args: args.into_iter().map(|a| ast::Expression::Variable(Box::new((range::Range::empty(0), a)))).collect(),
+        f_index: module.find_function(&Arc::new(function.into()), 0),
+        custom_source: None,
+        info: Box::new(ast::CallInfo {
+            name: Arc::new(function.into()),
+            alias: None,
+            source_range: range::Range::empty(0)
+        })
+    };
+    runtime.call(&call, module).map(|a| a.0)
+}
+
+fn run_job(modules: &ModuleCache, runtime: &mut Runtime, job: Call, limits: &Limits) -> Reply {
+    let module = match modules.get(&job.path) {
+        Ok(module) => module,
+        Err(e) => return Reply { result: Err(ScriptError::Failed(e.to_string())), effects: vec![], state: HashMap::new(), retire: false }
+    };
+    let args: Vec<Variable> = job.args.into_iter().map(Value::into_variable).collect();
+    let state = args.iter().find_map(|a| match a {
+        Variable::RustObject(object) => Some((object.clone(), script_state(object))),
+        _ => None
+    });
+
+    script_api::begin(job.context);
+    timers::start_handles_at(job.first_handle);
+    EFFECTS.set(0);
+    MAX_EFFECTS.set(Some(limits.effects));
+    limit_memory(Some(limits.memory));
+    let result = panic::catch_unwind(AssertUnwindSafe(|| call(runtime, &module, &job.function, args)));
+    limit_memory(None);
+    MAX_EFFECTS.set(None);
+    let effects = script_api::finish();
+
+    let result = match result {
+        Ok(Ok(value)) => Ok(value.as_ref().map(Value::from_variable)),
+        Ok(Err(e)) => Err(ScriptError::Failed(e)),
+        Err(payload) => {
+            // Whatever it was in the middle of is gone, start clean
+            *runtime = Runtime::new();
+            if payload.is::<TooManyEffects>() {
+                Err(ScriptError::Stopped(format!{"it asked for more than {} tells, moves, objects and timers", limits.effects}))
+            } else {
+                Err(ScriptError::Failed("the script crashed the interpreter".into()))
+            }
+        }
+    };
+    let state = match state {
+        Some((object, before)) => script_state(&object).into_iter().filter(|(k, v)| before.get(k) != Some(v)).collect(),
+        None => HashMap::new()
+    };
+    Reply { result, effects, state, retire: false }
+}
+
+fn parse_job(limits: &Limits, parse: impl FnOnce() -> Result<(), String>) -> Reply {
+    limit_memory(Some(limits.memory));
+    let result = panic::catch_unwind(AssertUnwindSafe(parse));
+    limit_memory(None);
+    let result = match result {
+        Ok(Ok(())) => Ok(None),
+        Ok(Err(e)) => Err(ScriptError::Failed(e)),
+        Err(_) => Err(ScriptError::Failed("the script crashed the parser".into()))
+    };
+    Reply { result, effects: vec![], state: HashMap::new(), retire: false }
+}
+
+fn work(mut replies: Box<dyn Write + Send>, limits: Limits) -> io::Result<()> {
+    let modules = ModuleCache::default();
+    let mut runtime = Runtime::new();
+    let started = address_space();
+    for line in io::stdin().lock().lines() {
+        let job: Job = serde_json::from_str(&line?).map_err(io::Error::other)?;
+        let mut reply = match job {
+            Job::Call(call) => run_job(&modules, &mut runtime, call, &limits),
+            Job::Validate { path, source } => parse_job(&limits, || dyon_inter::validate(&path, &source)),
+            Job::Load { path } => parse_job(&limits, || modules.get(&path).map(|_| ()).map_err(|e| e.to_string()))
+        };
+        reply.retire = matches!((started, address_space()), (Some(started), Some(now)) if now > started + limits.memory);
+        serde_json::to_writer(&mut replies, &reply).map_err(io::Error::other)?;
+        replies.write_all(b"\n")?;
+        replies.flush()?;
+        if reply.retire {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// server script-worker <memory kb> <effects> <timer secs>, started by the sandbox and fed jobs on stdin until the server goes
+pub fn run_worker(args: &[String]) -> io::Result<()> {
+    let number = |n: usize| args.get(n).and_then(|a| a.parse::<usize>().ok())
+        .ok_or_else(|| io::Error::other("usage: server script-worker <memory kb> <effects> <timer secs>"));
+    // Time is kept by the server, it kills a worker whose script runs too long
+    let limits = Limits { time: Duration::ZERO, memory: number(0)? * 1024, effects: number(1)?, timer_secs: number(2)? as u64 };
+    timers::set_max_delay(limits.timer_secs);
+    let replies = take_stdout()?;
+    // Stopped scripts are reported back, not as a crash
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if !info.payload().is::<TooManyEffects>() {
+            default_hook(info);
+        }
+    }));
+    thread::Builder::new()
+        .name("script worker".into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || work(replies, limits))?
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::other("the script worker crashed")))
+}
+
+// A worker process running one script at a time
+struct Worker {
+    process: Child,
+    jobs: ChildStdin,
+    replies: Lines<BufReader<ChildStdout>>
+}
+
+impl Worker {
+    fn spawn(limits: &Limits) -> io::Result<Self> {
+        let mut process = Command::new(std::env::current_exe()?)
+            .arg("script-worker")
+            .arg((limits.memory / 1024).to_string())
+            .arg(limits.effects.to_string())
+            .arg(limits.timer_secs.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            // Goes when the server or the benchmark's sandbox does
+            .kill_on_drop(true)
+            .spawn()?;
+        let jobs = process.stdin.take().ok_or_else(|| io::Error::other("the script worker has no stdin"))?;
+        let replies = process.stdout.take().ok_or_else(|| io::Error::other("the script worker has no stdout"))?;
+        Ok(Self { process, jobs, replies: BufReader::new(replies).lines() })
+    }
+
+    async fn send(&mut self, job: &str) -> io::Result<()> {
+        self.jobs.write_all(job.as_bytes()).await?;
+        self.jobs.flush().await
+    }
+}
+
+// A slot taken from the pool with the permit for it, both go back when it drops, even if whoever
+// held it stopped waiting partway through a job. The worker is out of the slot while it runs one
+// and goes with the job, so the slot comes back empty and the next job starts a fresh worker.
+struct Lease<'a> {
+    slot: Option<Worker>,
+    idle: &'a std::sync::Mutex<Vec<Option<Worker>>>,
+    _permit: SemaphorePermit<'a>
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        // Back in the pool before the permit, so whoever takes the permit finds it
+        self.idle.lock().unwrap_or_else(|e| e.into_inner()).push(self.slot.take());
+    }
+}
+
+// A pool of worker processes. Each keeps its own dyon runtime and parsed scripts, and one whose
+// script breaks a limit is killed or dies on its own, taking the script with it.
+pub struct Sandbox {
+    limits: Limits,
+    count: usize,
+    // One permit per idle worker
+    free: Semaphore,
+    // Workers start on first use and again after one is lost, so an idle slot can be empty
+    idle: std::sync::Mutex<Vec<Option<Worker>>>
+}
+
+impl Sandbox {
+    pub fn new(config: &ServerConfig) -> std::io::Result<Self> {
+        let limits = Limits::new(config);
+        let count = match config.script_workers {
+            0 => thread::available_parallelism().map(|a| a.get()).unwrap_or(4),
+            count => count
+        };
+        let idle = (0..count).map(|_| None).collect();
+        Ok(Self { limits, count, free: Semaphore::new(count), idle: std::sync::Mutex::new(idle) })
+    }
+
+    pub fn workers(&self) -> usize {
+        self.count
+    }
+
+    // Runs fn <function>(args) from the script at path with the context its script api fns read,
+    // waiting for a free worker first. Script state in args gets what the script set.
+    pub async fn call(&self, path: &str, function: &str, args: Vec<Variable>, context: ScriptContext) -> ScriptResult {
+        let job = Job::Call(Call {
+            path: path.into(),
+            function: function.into(),
+            args: args.iter().map(Value::from_variable).collect(),
+            context,
+            // Every timer is an effect, the one past the limit still takes a handle before it is stopped
+            first_handle: timers::reserve_handles(self.limits.effects as u64 + 1)
+        });
+        let reply = self.submit(&job).await?;
+        if !reply.state.is_empty() {
+            for arg in &args {
+                if let Variable::RustObject(object) = arg {
+                    dyon_inter::with_client_state(object, |state| state.extend(reply.state.clone()));
+                }
+            }
+        }
+        reply.result.map(|value| (value.map(Value::into_variable), reply.effects))
+    }
+
+    // Checks an upload compiles and has a usable home, Failed has Dyon's diagnostics
+    pub async fn validate(&self, path: &str, source: &str) -> Result<(), ScriptError> {
+        self.submit(&Job::Validate { path: path.into(), source: source.into() }).await?.result.map(|_| ())
+    }
+
+    // Parses the script at path in one worker, the others parse it on their next call to it
+    pub async fn load(&self, path: &str) -> Result<(), ScriptError> {
+        self.submit(&Job::Load { path: path.into() }).await?.result.map(|_| ())
+    }
+
+    async fn submit(&self, job: &Job) -> Result<Reply, ScriptError> {
+        let job = format!{"{}\n", serde_json::to_string(job).map_err(io::Error::other)?};
+        let permit = self.free.acquire().await.map_err(|_| ScriptError::Failed("the script workers are gone".into()))?;
+        // A permit always has a slot, an empty one if that were ever not so
+        let slot = self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop().flatten();
+        let mut lease = Lease { slot, idle: &self.idle, _permit: permit };
+        self.run(&mut lease.slot, &job).await
+    }
+
+    // The time limit only starts once a worker has the job, not while it waits for one
+    async fn run(&self, slot: &mut Option<Worker>, job: &str) -> Result<Reply, ScriptError> {
+        let mut worker = match slot.take() {
+            Some(worker) => worker,
+            None => Worker::spawn(&self.limits)?
+        };
+        // One that went away while idle never saw the job, a new one can take it
+        if worker.send(job).await.is_err() {
+            worker = Worker::spawn(&self.limits)?;
+            worker.send(job).await?;
+        }
+
+        let reply = match tokio::time::timeout(self.limits.time, worker.replies.next_line()).await {
+            Ok(Ok(Some(reply))) => reply,
+            Ok(_) => {
+                // Aborted partway through, out of memory or out of stack
+                let _ = worker.process.wait().await;
+                return Err(ScriptError::Stopped(format!{"it used more than {}kb of memory or recursed too deeply", self.limits.memory / 1024}));
+            },
+            Err(_) => {
+                let _ = worker.process.kill().await;
+                return Err(ScriptError::Stopped(format!{"it ran for longer than {}ms", self.limits.time.as_millis()}));
+            }
+        };
+        let reply: Reply = serde_json::from_str(&reply).map_err(|e| ScriptError::Failed(format!{"the script worker replied with nonsense, {}", e}))?;
+        if !reply.retire {
+            *slot = Some(worker);
+        }
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dropped_leases_go_back_to_the_pool() {
+        let sandbox = Sandbox::new(&ServerConfig { script_workers: 1, ..Default::default() }).unwrap();
+        let slot = sandbox.idle.lock().unwrap().pop().flatten();
+        let lease = Lease { slot, idle: &sandbox.idle, _permit: sandbox.free.try_acquire().unwrap() };
+        assert!(sandbox.free.try_acquire().is_err());
+        drop(lease);
+        assert_eq!(sandbox.idle.lock().unwrap().len(), 1);
+        assert!(sandbox.free.try_acquire().is_ok());
+    }
+}
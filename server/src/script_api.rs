@@ -0,0 +1,114 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use serde_derive::{Serialize, Deserialize};
+
+use crate::{states::{ClientPointer, GameObject, RoomAddr, ServerState}, timers::{self, TimerRequest}};
+
+// What a script can read, taken just before it runs so it never waits on a lock
+#[derive(Default, Serialize, Deserialize)]
+pub struct ScriptContext {
+    pub player: String,
+    pub room: RoomAddr,
+    pub room_display: String,
+    pub room_objects: Vec<String>,
+    pub room_links: Vec<RoomAddr>,
+    // health, max_health, stamina, max_stamina, skill levels and resources by name
+    pub stats: HashMap<String, i64>
+}
+
+// What a script asked for, applied in order once it returns
+#[derive(Serialize, Deserialize)]
+pub enum ScriptEffect {
+    Tell(String),
+    TellRoom(String),
+    Move(RoomAddr),
+    AddObject { name: String, display: String },
+    RemoveObject(String),
+    Timer(TimerRequest)
+}
+
+thread_local! {
+    // Scripts run start to finish on a sandbox worker's script thread
+    static CONTEXT: RefCell<ScriptContext> = RefCell::new(ScriptContext::default());
+    static EFFECTS: RefCell<Vec<ScriptEffect>> = const { RefCell::new(vec![]) };
+}
+
+pub async fn snapshot(server_state: &ServerState, client: &ClientPointer) -> ScriptContext {
+    let mut context = {
+        let client_ref = client.lock().await;
+        let mut stats = HashMap::from([
+            ("health".to_string(), client_ref.health),
+            ("max_health".to_string(), client_ref.max_health),
+            ("stamina".to_string(), client_ref.stamina),
+            ("max_stamina".to_string(), client_ref.max_stamina)
+        ]);
+        for skill in client_ref.skills.keys() {
+            stats.insert(skill.clone(), client_ref.skill_level(skill));
+        }
+        stats.extend(client_ref.resources.iter().map(|(a, b)| (a.clone(), *b)));
+        ScriptContext { player: client_ref.name.clone(), room: client_ref.current_room.clone(), stats, ..Default::default() }
+    };
+    if let Some(room) = server_state.get_room(&context.room).await {
+        let room_ref = room.lock().await;
+        context.room_display = room_ref.display.clone();
+        context.room_objects = room_ref.objects.keys().cloned().collect();
+        context.room_objects.sort();
+        context.room_links = room_ref.links.clone();
+    }
+    context
+}
+
+// Call right before the script, with nothing awaited in between
+pub fn begin(context: ScriptContext) {
+    CONTEXT.with(|a| *a.borrow_mut() = context);
+    EFFECTS.with(|a| a.borrow_mut().clear());
+}
+
+// Call right after the script, hands back what it asked for
+pub fn finish() -> Vec<ScriptEffect> {
+    CONTEXT.with(|a| *a.borrow_mut() = ScriptContext::default());
+    EFFECTS.with(|a| a.take())
+}
+
+pub fn with_context<T>(f: impl FnOnce(&ScriptContext) -> T) -> T {
+    CONTEXT.with(|a| f(&a.borrow()))
+}
+
+pub fn push(effect: ScriptEffect) {
+    crate::sandbox::count_effect();
+    EFFECTS.with(|a| a.borrow_mut().push(effect));
+}
+
+// Carries out a script's effects for the player it ran for, script names the file for its timers
+pub async fn apply(server_state: &ServerState, client: &ClientPointer, script: &str, effects: Vec<ScriptEffect>) {
+    let name = client.lock().await.name.clone();
+    let mut timer_requests = vec![];
+    for effect in effects {
+        let room_addr = client.lock().await.current_room.clone();
+        match effect {
+            ScriptEffect::Tell(text) => client.lock().await.send(text),
+            ScriptEffect::TellRoom(text) => server_state.send_to_room(&room_addr, text, None).await,
+            ScriptEffect::Move(to) => {
+                if server_state.get_room(&to).await.is_none() {
+                    eprintln!("Script {} tried to move {} to missing room {}", script, name, to);
+                    continue;
+                }
+                server_state.teleport(client, &to).await;
+            },
+            ScriptEffect::AddObject { name: object_name, display } => {
+                if let Some(room) = server_state.get_room(&room_addr).await {
+                    let mut room_ref = room.lock().await;
+                    let object = room_ref.objects.entry(object_name.clone()).or_insert_with(|| GameObject::new(object_name));
+                    object.display = display;
+                }
+            },
+            ScriptEffect::RemoveObject(object_name) => {
+                if let Some(room) = server_state.get_room(&room_addr).await {
+                    room.lock().await.objects.remove(&object_name);
+                }
+            },
+            ScriptEffect::Timer(request) => timer_requests.push(request)
+        }
+    }
+    timers::apply(server_state, &name, script, timer_requests);
+}
@@ -0,0 +1,79 @@
+use serde_derive::{Serialize, Deserialize};
+
+use crate::config::{self, ServerConfig};
+
+// Set per action by builders, within the caps in config.json
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SkillRequirement {
+    pub skill: String,
+    pub difficulty: i64,
+    // Lowest level in the skill that can do the action
+    pub level: i64
+}
+
+// Total xp needed to reach a level, everyone starts at level 1
+pub fn xp_for_level(level: i64) -> i64 {
+    50 * (level - 1).pow(2)
+}
+
+pub fn level_for_xp(xp: i64) -> i64 {
+    let mut level = 1;
+    while xp_for_level(level + 1) <= xp {
+        level += 1;
+    }
+    level
+}
+
+// difficulty * level, less the further the player has outgrown the action, never over the cap
+pub fn xp_reward(requirement: &SkillRequirement, player_level: i64, config: &ServerConfig) -> i64 {
+    let base = requirement.difficulty * requirement.level;
+    let outgrown = (player_level - requirement.level).max(0);
+    (base / (1 + outgrown)).min(config.max_action_xp)
+}
+
+pub fn check_caps(requirement: &SkillRequirement, config: &ServerConfig) -> Result<(), String> {
+    config::check_cap("Difficulty", requirement.difficulty, 1, config.max_action_difficulty)?;
+    config::check_cap("Level", requirement.level, 1, config.max_action_level)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requirement(difficulty: i64, level: i64) -> SkillRequirement {
+        SkillRequirement { skill: "mining".into(), difficulty, level }
+    }
+
+    #[test]
+    fn levels_start_at_one_and_follow_xp_for_level() {
+        assert_eq!(level_for_xp(0), 1);
+        assert_eq!(level_for_xp(49), 1);
+        assert_eq!(level_for_xp(50), 2);
+        assert_eq!(level_for_xp(199), 2);
+        assert_eq!(level_for_xp(200), 3);
+        assert_eq!(level_for_xp(-10), 1);
+        for level in 1..50 {
+            assert_eq!(level_for_xp(xp_for_level(level)), level);
+        }
+    }
+
+    #[test]
+    fn rewards_shrink_once_outgrown_and_stay_under_the_cap() {
+        let config = ServerConfig { max_action_xp: 250, ..Default::default() };
+        assert_eq!(xp_reward(&requirement(5, 10), 10, &config), 50);
+        assert_eq!(xp_reward(&requirement(5, 10), 1, &config), 50);
+        assert_eq!(xp_reward(&requirement(5, 10), 11, &config), 25);
+        assert_eq!(xp_reward(&requirement(5, 10), 19, &config), 5);
+        assert_eq!(xp_reward(&requirement(10, 100), 100, &config), 250);
+    }
+
+    #[test]
+    fn caps_say_which_one_was_broken() {
+        let config = ServerConfig { max_action_difficulty: 10, max_action_level: 100, ..Default::default() };
+        assert_eq!(check_caps(&requirement(10, 100), &config), Ok(()));
+        assert_eq!(check_caps(&requirement(0, 5), &config), Err("Difficulty has to be between 1 and 10.".into()));
+        assert_eq!(check_caps(&requirement(11, 5), &config), Err("Difficulty has to be between 1 and 10.".into()));
+        assert_eq!(check_caps(&requirement(5, 101), &config), Err("Level has to be between 1 and 100.".into()));
+        assert_eq!(check_caps(&requirement(5, 0), &config), Err("Level has to be between 1 and 100.".into()));
+    }
+}
@@ -1,11 +1,12 @@
-use std::{net::SocketAddr, sync::Arc, collections::{HashSet, HashMap}, fs::OpenOptions, io::{BufReader, BufWriter, Write, Read}};
+use std::{net::SocketAddr, sync::Arc, collections::{HashSet, HashMap, hash_map::DefaultHasher}, hash::{Hash, Hasher}, time::{Instant, SystemTime, UNIX_EPOCH}};
 
-use dyon::Runtime;
 use lazy_static::lazy_static;
 use regex::Regex;
 use serde::{ser::SerializeStruct, Deserializer};
 use serde_derive::{Serialize, Deserialize};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, watch, mpsc::UnboundedSender};
+
+use crate::{accounts::LoginThrottle, channels::Channels, config::ServerConfig, items::{ItemRng, ItemTemplate}, npcs::{Npc, SpawnRule}, sandbox::{Sandbox, ScriptError}, skills::{self, SkillRequirement}, storage::WorldStore, timers::{self, Timer}};
 
 pub fn to_arc_mutex<T>(owned: T) -> Arc<Mutex<T>> {
     Arc::new(Mutex::new(owned))
@@ -16,68 +17,209 @@ where
     D: Deserializer<'de>,
 {
     let map_val: HashMap<String, String> = serde::de::Deserialize::deserialize(d)?;
-    return Ok(Arc::new(std::sync::Mutex::new(map_val)));
+    Ok(Arc::new(std::sync::Mutex::new(map_val)))
+}
+
+// Cheap fingerprint of what would be written to the store, used to skip unchanged saves
+fn save_hash<T: serde::Serialize>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_string(value).unwrap_or_default().hash(&mut hasher);
+    hasher.finish()
+}
+
+fn starting_health() -> i64 {
+    crate::combat::STARTING_HEALTH
+}
+
+fn starting_stamina() -> i64 {
+    crate::combat::STARTING_STAMINA
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
 }
 
 pub type ClientPointer = Arc<Mutex<ClientState>>;
 
 pub type RoomPointer = Arc<Mutex<Room>>;
 
-#[derive(Deserialize)]
+pub type Outbox = UnboundedSender<String>;
+
+// Clones share client_script_states, they are only made to hand a copy to the store
+#[derive(Deserialize, Clone)]
 pub struct ClientState {
     pub addr: Option<SocketAddr>,
 
     pub is_edit_mode: bool,
     pub current_room: RoomAddr,
     pub name: String,
+    // Argon2 PHC string, empty for accounts saved before passwords existed
+    #[serde(default)]
+    pub password_hash: String,
+    #[serde(default)]
+    pub role: Role,
+    // Channels they are on, and the ones of those they are not hearing right now
+    #[serde(default)]
+    pub channels: Vec<String>,
+    #[serde(default)]
+    pub muted_channels: Vec<String>,
+    // Unix seconds of their last login or logout
+    #[serde(default)]
+    pub last_seen: u64,
+    #[serde(default)]
+    pub inventory: Vec<GameObject>,
+    // Xp by skill name, levels come from skills::level_for_xp
+    #[serde(default)]
+    pub skills: HashMap<String, i64>,
+    // Skill trained while logged out, and what it has earned, e.g. coins
+    #[serde(default)]
+    pub idle_activity: Option<String>,
+    #[serde(default)]
+    pub resources: HashMap<String, i64>,
+    // Unix seconds of their last logout, 0 while they are logged in
+    #[serde(default)]
+    pub last_logout: u64,
+    #[serde(default = "starting_health")]
+    pub health: i64,
+    #[serde(default = "starting_health")]
+    pub max_health: i64,
+    #[serde(default = "starting_stamina")]
+    pub stamina: i64,
+    #[serde(default = "starting_stamina")]
+    pub max_stamina: i64,
+    // The weapon in hand, out of the inventory while it is there
+    #[serde(default)]
+    pub equipped: Option<GameObject>,
+    #[serde(skip)]
+    pub last_attack: Option<Instant>,
+    #[serde(skip, default = "Instant::now")]
+    pub last_input: Instant,
     // Should work...
     #[serde(deserialize_with = "hmmutex")]
-    pub client_script_states: Arc<std::sync::Mutex<HashMap<String, String>>>
+    pub client_script_states: Arc<std::sync::Mutex<HashMap<String, String>>>,
+    // Lines pushed here are written to the player's socket between their own commands
+    #[serde(skip)]
+    pub outbox: Option<Outbox>,
+    // Who reply answers, forgotten on logout
+    #[serde(skip)]
+    pub last_whisper_from: Option<String>
 }
 
 impl serde::Serialize for ClientState {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer {
-        let mut client_state = serializer.serialize_struct("ClientState", 4)?;
+        let mut client_state = serializer.serialize_struct("ClientState", 19)?;
         client_state.serialize_field("is_edit_mode", &self.is_edit_mode)?;
         client_state.serialize_field("current_room", &self.current_room)?;
         client_state.serialize_field("name", &self.name)?;
+        client_state.serialize_field("password_hash", &self.password_hash)?;
+        client_state.serialize_field("role", &self.role)?;
+        client_state.serialize_field("channels", &self.channels)?;
+        client_state.serialize_field("muted_channels", &self.muted_channels)?;
+        client_state.serialize_field("last_seen", &self.last_seen)?;
+        client_state.serialize_field("inventory", &self.inventory)?;
+        client_state.serialize_field("skills", &self.skills)?;
+        client_state.serialize_field("idle_activity", &self.idle_activity)?;
+        client_state.serialize_field("resources", &self.resources)?;
+        client_state.serialize_field("last_logout", &self.last_logout)?;
+        client_state.serialize_field("health", &self.health)?;
+        client_state.serialize_field("max_health", &self.max_health)?;
+        client_state.serialize_field("stamina", &self.stamina)?;
+        client_state.serialize_field("max_stamina", &self.max_stamina)?;
+        client_state.serialize_field("equipped", &self.equipped)?;
         client_state.serialize_field("client_script_states",
                                      &self.client_script_states.lock()
                                      .map(|a| a.clone())
-                                     .unwrap_or(HashMap::<String, String>::new()))?;
+                                     .unwrap_or_else(|e| e.into_inner().clone()))?;
         client_state.end()
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Player,
+    Builder,
+    Admin
+}
+
+impl Role {
+    pub fn parse(string: &str) -> Option<Role> {
+        match string.to_lowercase().as_str() {
+            "player" => Some(Role::Player),
+            "builder" => Some(Role::Builder),
+            "admin" => Some(Role::Admin),
+            _ => None
+        }
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Role::Player => write!(f, "player"),
+            Role::Builder => write!(f, "builder"),
+            Role::Admin => write!(f, "admin")
+        }
+    }
+}
+
 pub type RoomAddr = String;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub enum GameAction {
     None,
     PrintText(String),
-    RunScript(String)
+    RunScript(String),
+    // Rolls an item from the named template into the player's inventory
+    SpawnItem(String)
 }
 
 impl GameAction {
-    pub async fn handle(&self, _client_state: ClientPointer, _room: RoomPointer, _runtime: Arc<Mutex<Runtime>>) -> String {
+    // source says what ran it, e.g. "sign read for bob", for the log if its script has to be stopped
+    // Err is what to tell them when the action did not happen, like a script that failed
+    pub async fn handle(&self, _client_state: ClientPointer, _room: RoomPointer, server_state: Arc<ServerState>, source: &str) -> Result<String, String> {
         match *self {
             Self::PrintText(ref some) =>  {
-                return some.clone();
+                Ok(some.clone())
             },
             Self::RunScript(ref some) =>  {
-                let return_type = crate::dyon_inter::load_and_run(&format!{ "dyon/{}.dyon", some }, _client_state.clone(), &_runtime).await;
-                if return_type.is_err() {
-                    return format! { "Code error, script did not return a String" };
+                // Actions saved before \action checked the name could point anywhere
+                if !crate::accounts::valid_name(some) {
+                    return Err(format!{"{} is not a valid script name.", some});
+                }
+                let return_type = crate::dyon_inter::load_and_run(&format!{ "dyon/{}.dyon", some }, _client_state.clone(), &server_state, source).await;
+                let (return_type, effects) = match return_type {
+                    Ok(result) => result,
+                    Err(ScriptError::Failed(e)) => {
+                        eprintln!("Script {} failed for {}: {}", some, source, e);
+                        // Builders get Dyon's error with where it happened, players just that it broke
+                        if _client_state.lock().await.role >= Role::Builder {
+                            return Err(format!{"The {} script failed:\n{}", some, e.trim()});
+                        }
+                        return Err("Code error, script did not return a String".into())
+                    },
+                    Err(e) => return Err(format! { "The {} script was stopped, {}.", some, e })
+                };
+                crate::script_api::apply(&server_state, &_client_state, some, effects).await;
+                if let Some(dyon::Variable::Str(arc_str)) = return_type {
+                    return Ok((*arc_str).clone());
                 }
-                let return_type = return_type.unwrap().0.unwrap();
-                if let dyon::Variable::Str(arc_str) = return_type {
-                    return (*arc_str).clone();
+                Err("Script returned non-string type in home fn".into())
+            }
+            Self::SpawnItem(ref template) => {
+                let item = server_state.spawn_item(template);
+                if item.is_none() {
+                    return Err(format!{"There is no item template called {}.", template});
                 }
-                format!{ "Script returned non-string type in home fn" }
+                let item = item.unwrap();
+                let message = format!{"You receive a {}.", item.name};
+                _client_state.lock().await.inventory.push(item);
+                Ok(message)
             }
-            _ => { String::from("Unhandled") }
+            _ => { Err(String::from("Unhandled")) }
         }
     }
 
@@ -87,6 +229,7 @@ impl GameAction {
         lazy_static! {
             static ref PRINT_TEXT_REGEX: Regex = Regex::new("PrintText (.+)").unwrap();
             static ref RUN_SCRIPT_REGEX: Regex = Regex::new("RunScript (.+)").unwrap();
+            static ref SPAWN_ITEM_REGEX: Regex = Regex::new("SpawnItem (.+)").unwrap();
         }
 
         if PRINT_TEXT_REGEX.is_match(string.as_str()) {
@@ -97,6 +240,10 @@ impl GameAction {
             return RunScript(RUN_SCRIPT_REGEX.captures(&string).unwrap().get(1).expect("Regex error").as_str().into())
         }
 
+        if SPAWN_ITEM_REGEX.is_match(string.as_str()) {
+            return SpawnItem(SPAWN_ITEM_REGEX.captures(&string).unwrap().get(1).expect("Regex error").as_str().into())
+        }
+
         GameAction::None
     }
 }
@@ -105,7 +252,16 @@ impl GameAction {
 pub struct GameObject {
     pub display: String,
     pub name: String,
-    pub actions: HashMap<String, GameAction>
+    pub actions: HashMap<String, GameAction>,
+    // Can be picked up, everything else is scenery like the nexus sign
+    #[serde(default)]
+    pub portable: bool,
+    // Rolled by an item template, e.g. power
+    #[serde(default)]
+    pub stats: HashMap<String, i64>,
+    // By action name, actions without one need no skill and teach nothing
+    #[serde(default)]
+    pub requirements: HashMap<String, SkillRequirement>
 }
 
 impl GameObject {
@@ -113,7 +269,10 @@ impl GameObject {
         Self {
             name: name.clone(),
             display: name,
-            actions: HashMap::new()
+            actions: HashMap::new(),
+            portable: false,
+            stats: HashMap::new(),
+            requirements: HashMap::new()
         }
     }
 }
@@ -122,10 +281,16 @@ impl GameObject {
 pub struct Room {
     pub addr: RoomAddr,
     pub display: String,
-    // Clients by their address
+    // Clients by their address, only meaningful while the server is up
+    #[serde(skip)]
     pub clients: HashSet<SocketAddr>,
     pub links: Vec<RoomAddr>,
-    pub objects: HashMap<String, GameObject>
+    pub objects: HashMap<String, GameObject>,
+    #[serde(default)]
+    pub spawns: Vec<SpawnRule>,
+    // Whatever the spawn rules have put here, rebuilt after a restart
+    #[serde(skip)]
+    pub npcs: Vec<Npc>
 }
 
 impl Room {
@@ -145,11 +310,67 @@ impl ClientState {
             is_edit_mode: false,
             current_room: "nexus".into(),
             name: String::new(),
-            client_script_states: Arc::new(std::sync::Mutex::new(HashMap::<String, String>::new()))
+            password_hash: String::new(),
+            role: Role::Player,
+            channels: vec![],
+            muted_channels: vec![],
+            last_seen: 0,
+            inventory: vec![],
+            skills: HashMap::new(),
+            idle_activity: None,
+            resources: HashMap::new(),
+            last_logout: 0,
+            health: starting_health(),
+            max_health: starting_health(),
+            stamina: starting_stamina(),
+            max_stamina: starting_stamina(),
+            equipped: None,
+            last_attack: None,
+            last_input: Instant::now(),
+            client_script_states: Arc::new(std::sync::Mutex::new(HashMap::<String, String>::new())),
+            outbox: None,
+            last_whisper_from: None
         }
     }
 
-    pub fn to_pointer(self) -> ClientPointer 
+    // Fire and forget, a player who already left just misses it
+    pub fn send(&self, message: String) {
+        if let Some(outbox) = &self.outbox {
+            let _ = outbox.send(message);
+        }
+    }
+
+    pub fn logged_out(&mut self) {
+        self.last_seen = unix_now();
+        self.last_logout = self.last_seen;
+    }
+
+    pub fn skill_level(&self, skill: &str) -> i64 {
+        skills::level_for_xp(self.skills.get(skill).copied().unwrap_or(0))
+    }
+
+    // Returns the new level if this gain went up one
+    pub fn add_xp(&mut self, skill: &str, xp: i64) -> Option<i64> {
+        let before = self.skill_level(skill);
+        *self.skills.entry(skill.into()).or_insert(0) += xp;
+        let after = self.skill_level(skill);
+        if after > before {
+            return Some(after);
+        }
+        None
+    }
+
+    pub fn carried(&self, name: &str) -> Option<&GameObject> {
+        self.inventory.iter().find(|a| a.name == name)
+    }
+
+    // Takes the first item called name out of the inventory
+    pub fn take_carried(&mut self, name: &str) -> Option<GameObject> {
+        let index = self.inventory.iter().position(|a| a.name == name)?;
+        Some(self.inventory.remove(index))
+    }
+
+    pub fn into_pointer(self) -> ClientPointer 
     {
         to_arc_mutex(self)
     }
@@ -158,24 +379,34 @@ impl ClientState {
 pub struct ServerState {
     pub client_states: Arc<Mutex<Vec<ClientPointer>>>,
     pub rooms: Mutex<HashMap<RoomAddr, Arc<Mutex<Room>>>>,
-    pub runtime: Arc<Mutex<dyon::Runtime>>
+    pub sandbox: Sandbox,
+    pub store: Arc<dyn WorldStore>,
+    pub config: ServerConfig,
+    pub login_throttle: LoginThrottle,
+    // Names with a connection playing them, held from a successful login until they are saved on the way out
+    playing: std::sync::Mutex<HashSet<String>>,
+    pub channels: Channels,
+    pub item_templates: std::sync::Mutex<HashMap<String, ItemTemplate>>,
+    // Waiting script callbacks and delayed messages, saved along with players
+    pub timers: std::sync::Mutex<Vec<Timer>>,
+    // Item rolls only, seeded by item_seed so drops can be reproduced
+    item_rng: std::sync::Mutex<ItemRng>,
+    // Fights, npcs and everything else that rolls
+    rng: std::sync::Mutex<ItemRng>,
+    // Flipped to true once when the server starts shutting down
+    pub shutdown: watch::Sender<bool>,
+    // What each room ("room:<addr>") and player ("player:<name>") looked like when last saved
+    saved_hashes: std::sync::Mutex<HashMap<String, u64>>
 }
 
 impl ServerState {
-    pub fn new() -> Self {
-        // For now json database...
-        let mut options = OpenOptions::new();
-        const PATH: &'static str = "database/world.json";
-        options.create(true).read(true).write(true);
-        std::fs::create_dir_all("database/").expect("Failed to create database directory!");
-        let database_file = options.open(PATH).expect(&format!{"Failed to open {}", PATH});
-        let database_file = BufReader::new(database_file);
-        // empty hashmap in case file is empty
-        let map: HashMap<RoomAddr, Room> = serde_json::from_reader(database_file).unwrap_or(HashMap::new());
+    pub fn new(config: ServerConfig, store: Box<dyn WorldStore>) -> std::io::Result<Self> {
+        // Refuse to start on an unreadable world rather than overwrite it with an empty one
+        let map: HashMap<RoomAddr, Room> = store.load_rooms()?;
         let mut map: HashMap<RoomAddr, Arc<Mutex<Room>>> = map.into_iter()
             .map(|(a, b)| (a, to_arc_mutex(b))).collect();
 
-        if map.len() == 0 {
+        if map.is_empty() {
             map.insert("nexus".into(), to_arc_mutex(Room {
                 addr: "nexus".into(),
                 display: "The room is quiet... Except for a [@Csign].".into(),
@@ -190,75 +421,342 @@ impl ServerState {
                             HashMap::from([
                                 ("read".into(), GameAction::PrintText("Good job, you learned how to interact with objects!".into()))
                             ])
-                        }
+                        },
+                        portable: false,
+                        stats: HashMap::new(),
+                        requirements: HashMap::new()
                     });
                     some_hash
                 },
+                ..Default::default()
             }));
         }
 
-        Self {
+        let item_templates = store.load_templates()?;
+        let pending_timers = store.load_timers()?;
+        timers::resume_handles(&pending_timers);
+        let clock_seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+        // A fixed seed in config.json makes drops reproducible
+        let item_seed = config.item_seed.unwrap_or(clock_seed);
+
+        // Whatever was just loaded is already on disk
+        let mut saved_hashes: HashMap<String, u64> = map.iter()
+            .map(|(a, b)| (format!{"room:{}", a}, save_hash(&*b.try_lock().unwrap())))
+            .collect();
+        saved_hashes.insert("timers".into(), save_hash(&pending_timers));
+
+        Ok(Self {
             client_states: to_arc_mutex(vec![]),
             rooms: Mutex::new(map),
-            runtime: to_arc_mutex(dyon::Runtime::new()),
-        }
+            sandbox: Sandbox::new(&config)?,
+            store: store.into(),
+            channels: Channels::new(&config.channels),
+            item_templates: std::sync::Mutex::new(item_templates),
+            timers: std::sync::Mutex::new(pending_timers),
+            item_rng: std::sync::Mutex::new(ItemRng::new(item_seed)),
+            rng: std::sync::Mutex::new(ItemRng::new(clock_seed.rotate_left(32))),
+            config,
+            login_throttle: LoginThrottle::default(),
+            playing: std::sync::Mutex::new(HashSet::new()),
+            shutdown: watch::channel(false).0,
+            saved_hashes: std::sync::Mutex::new(saved_hashes)
+        })
+    }
+
+    // Records hash as saved under key, returns false if it already was
+    fn mark_saved(&self, key: String, hash: u64) -> bool {
+        self.saved_hashes.lock().unwrap().insert(key, hash) != Some(hash)
+    }
+
+    fn is_dirty(&self, key: &str, hash: u64) -> bool {
+        self.saved_hashes.lock().unwrap().get(key) != Some(&hash)
+    }
+
+    // Stores block on files and sqlite, so every call after startup runs on tokio's blocking threads
+    pub async fn with_store<T: Send + 'static>(&self, f: impl FnOnce(&dyn WorldStore) -> std::io::Result<T> + Send + 'static) -> std::io::Result<T> {
+        let store = self.store.clone();
+        tokio::task::spawn_blocking(move || f(&*store)).await.map_err(std::io::Error::other)?
     }
 
     pub async fn save_client(&self, client_state: ClientPointer) -> std::io::Result<()> {
-        let mut options = OpenOptions::new();
-        let path: String = format!{"database/{}.json", client_state.lock().await.name};
-        options.create(true).write(true);
-        std::fs::create_dir_all("database/").expect("Failed to create database directory!");
-        let mut database_file = options.open(&path).expect(&format!{"Failed to open {}", path});
-        database_file.write(
-            serde_json::to_string(&*client_state.lock().await).unwrap().as_bytes()
-        )?;
+        let client = client_state.lock().await.clone();
+        let hash = save_hash(&client);
+        let key = format!{"player:{}", client.name};
+        self.with_store(move |store| store.save_account(&client)).await?;
+        self.mark_saved(key, hash);
         Ok(())
     }
 
-    pub async fn load_client(&self, name: String) -> Option<ClientState> {
-        let mut options = OpenOptions::new();
-        let path: String = format!{"database/{}.json", name};
-        options.create(false).read(true);
-        std::fs::create_dir_all("database/").expect("Failed to create database directory!");
-        let database_file = options.open(&path);
-        if database_file.is_err() {
-            return None;
-        }
-        let mut database_file = database_file.unwrap();
-        let mut string_buf = String::new();
-        database_file.read_to_string(&mut string_buf).expect("Failed to read in client");
-        return serde_json::from_str(&string_buf).map(|a| Some(a)).unwrap_or(None);
-    }
-
-    pub fn save(&self) -> std::io::Result<()> {
-        let mut options = OpenOptions::new();
-        const PATH: &'static str = "database/world.json";
-        options.create(true).write(true);
-        std::fs::create_dir_all("database/").expect("Failed to create database directory!");
-        let database_file = options.open(PATH).expect(&format!{"Failed to open {}", PATH});
-        let mut database_file = BufWriter::new(database_file);
-        let map: HashMap<String, Room> = self.rooms.try_lock().expect("Failed to acquire rooms").iter()
-            .map(|(a,b)| (a.clone(), b.try_lock().unwrap().clone())).collect();
-        database_file.write(serde_json::to_string(&map).unwrap().as_bytes())?;
-        Ok(())
+    // Returns whether anything was written
+    pub async fn save_client_if_dirty(&self, client_state: ClientPointer) -> std::io::Result<bool> {
+        let client = client_state.lock().await.clone();
+        // Still sitting at the name prompt
+        if client.name.is_empty() {
+            return Ok(false);
+        }
+        let key = format!{"player:{}", client.name};
+        let hash = save_hash(&client);
+        if !self.is_dirty(&key, hash) {
+            return Ok(false);
+        }
+        self.with_store(move |store| store.save_account(&client)).await?;
+        self.mark_saved(key, hash);
+        Ok(true)
+    }
+
+    // Checked and taken in one step so two connections can never both get in as the same character
+    pub fn claim_name(&self, name: &str) -> bool {
+        self.playing.lock().unwrap().insert(name.into())
+    }
+
+    pub fn release_name(&self, name: &str) {
+        self.playing.lock().unwrap().remove(name);
+    }
+
+    pub fn is_playing(&self, name: &str) -> bool {
+        self.playing.lock().unwrap().contains(name)
+    }
+
+    // Just what is saved, for checking a password before the name is claimed
+    pub async fn load_account(&self, name: &str) -> std::io::Result<Option<ClientState>> {
+        let name = name.to_string();
+        self.with_store(move |store| store.load_account(&name)).await
+    }
+
+    // Loads a player who is about to log in. An account that cannot be read is an error,
+    // never a missing one that a new player could register over.
+    pub async fn load_client(&self, name: String) -> std::io::Result<Option<ClientState>> {
+        let client = match self.load_account(&name).await? {
+            Some(client) => client,
+            None => return Ok(None)
+        };
+        self.mark_saved(format!{"player:{}", name}, save_hash(&client));
+        Ok(Some(client))
+    }
+
+    pub async fn save(&self) -> std::io::Result<()> {
+        self.save_rooms(false).await.map(|_| ())
     }
 
-    pub fn get_room(&self, addr: &RoomAddr) -> Option<Arc<Mutex<Room>>> {
-        let locked_room = self.rooms.try_lock();
-        if locked_room.is_err() {
-            return None;
+    // Returns how many rooms were written
+    pub async fn save_rooms(&self, only_dirty: bool) -> std::io::Result<usize> {
+        let rooms: Vec<Arc<Mutex<Room>>> = self.rooms.lock().await.values().cloned().collect();
+        let mut changed = HashMap::new();
+        let mut hashes = vec![];
+        for room in rooms {
+            let room = room.lock().await.clone();
+            let key = format!{"room:{}", room.addr};
+            let hash = save_hash(&room);
+            if !only_dirty || self.is_dirty(&key, hash) {
+                hashes.push((key, hash));
+                changed.insert(room.addr.clone(), room);
+            }
+        }
+        if changed.is_empty() {
+            return Ok(0);
         }
-        locked_room.unwrap().get(addr).map(|a| a.clone())
+        let count = changed.len();
+        self.with_store(move |store| store.save_rooms(&changed)).await?;
+        for (key, hash) in hashes {
+            self.mark_saved(key, hash);
+        }
+        Ok(count)
     }
 
-    pub fn new_room(&self, addr: &RoomAddr) {
-        let locked_room = self.rooms.try_lock();
-        if locked_room.is_err() {
-            return;
+    // Saves rooms and every connected player, returns (rooms, players) written
+    pub async fn save_all(&self, only_dirty: bool) -> std::io::Result<(usize, usize)> {
+        let rooms = self.save_rooms(only_dirty).await?;
+        self.save_timers(only_dirty).await?;
+        let clients: Vec<ClientPointer> = self.client_states.lock().await.clone();
+        let mut players = 0;
+        for client in clients {
+            if only_dirty {
+                players += self.save_client_if_dirty(client).await? as usize;
+            } else if !client.lock().await.name.is_empty() {
+                self.save_client(client).await?;
+                players += 1;
+            }
         }
-        locked_room.unwrap().insert(
+        Ok((rooms, players))
+    }
+
+    pub async fn get_room(&self, addr: &RoomAddr) -> Option<Arc<Mutex<Room>>> {
+        self.rooms.lock().await.get(addr).cloned()
+    }
+
+    pub async fn new_room(&self, addr: &RoomAddr) {
+        self.rooms.lock().await.insert(
             addr.clone(), Arc::new(Mutex::new(Room::new(addr.clone())))
         );
     }
+
+    pub fn spawn_item(&self, template: &str) -> Option<GameObject> {
+        let templates = self.item_templates.lock().unwrap();
+        let template = templates.get(template)?;
+        Some(template.roll(&mut self.item_rng.lock().unwrap()))
+    }
+
+    // Inclusive on both ends
+    pub fn roll(&self, low: i64, high: i64) -> i64 {
+        self.rng.lock().unwrap().range(low, high)
+    }
+
+    // Returns whether anything was written
+    pub async fn save_timers(&self, only_dirty: bool) -> std::io::Result<bool> {
+        let timers = self.timers.lock().unwrap().clone();
+        let hash = save_hash(&timers);
+        if only_dirty && !self.is_dirty("timers", hash) {
+            return Ok(false);
+        }
+        self.with_store(move |store| store.save_timers(&timers)).await?;
+        self.mark_saved("timers".into(), hash);
+        Ok(true)
+    }
+
+    pub async fn save_templates(&self) -> std::io::Result<()> {
+        let templates = self.item_templates.lock().unwrap().clone();
+        self.with_store(move |store| store.save_templates(&templates)).await
+    }
+
+    // Builders in edit mode drop out of who, look and room announcements
+    pub fn is_hidden(&self, client: &ClientState) -> bool {
+        client.is_edit_mode && self.config.invisible_editors
+    }
+
+    // Builders and admins see everyone
+    pub fn can_see(&self, viewer: Role, client: &ClientState) -> bool {
+        viewer >= Role::Builder || !self.is_hidden(client)
+    }
+
+    // Presence and messaging helpers below lock one client at a time, so never call them while holding a client lock
+
+    pub async fn find_player(&self, name: &str) -> Option<ClientPointer> {
+        let clients: Vec<ClientPointer> = self.client_states.lock().await.clone();
+        for client in clients {
+            if client.lock().await.name == name {
+                return Some(client);
+            }
+        }
+        None
+    }
+
+    // Returns false if nobody by that name is online
+    pub async fn send_to_player(&self, name: &str, message: String) -> bool {
+        match self.find_player(name).await {
+            Some(client) => {
+                client.lock().await.send(message);
+                true
+            },
+            None => false
+        }
+    }
+
+    // Everyone past the login prompt
+    pub async fn broadcast(&self, message: String, except: Option<&ClientPointer>) {
+        let clients: Vec<ClientPointer> = self.client_states.lock().await.clone();
+        for client in clients {
+            if except.map(|a| Arc::ptr_eq(a, &client)).unwrap_or(false) {
+                continue;
+            }
+            let client_ref = client.lock().await;
+            if !client_ref.name.is_empty() {
+                client_ref.send(message.clone());
+            }
+        }
+    }
+
+    pub async fn clients_in_room(&self, addr: &RoomAddr) -> Vec<ClientPointer> {
+        let room = self.get_room(addr).await;
+        if room.is_none() {
+            return vec![];
+        }
+        let present = room.unwrap().lock().await.clients.clone();
+        let clients: Vec<ClientPointer> = self.client_states.lock().await.clone();
+        let mut in_room = vec![];
+        for client in clients {
+            let client_addr = client.lock().await.addr;
+            if client_addr.map(|a| present.contains(&a)).unwrap_or(false) {
+                in_room.push(client);
+            }
+        }
+        in_room
+    }
+
+    pub async fn send_to_room(&self, addr: &RoomAddr, message: String, except: Option<&ClientPointer>) {
+        for client in self.clients_in_room(addr).await {
+            if except.map(|a| Arc::ptr_eq(a, &client)).unwrap_or(false) {
+                continue;
+            }
+            client.lock().await.send(message.clone());
+        }
+    }
+
+    // Everyone on the channel who has not muted it and still has the role for it
+    pub async fn send_to_channel(&self, channel: &str, message: String, except: Option<&ClientPointer>) {
+        let min_role = match self.channels.min_role(channel) {
+            Some(min_role) => min_role,
+            None => return
+        };
+        self.channels.record(channel, message.clone());
+        let clients: Vec<ClientPointer> = self.client_states.lock().await.clone();
+        for client in clients {
+            if except.map(|a| Arc::ptr_eq(a, &client)).unwrap_or(false) {
+                continue;
+            }
+            let client_ref = client.lock().await;
+            let listening = client_ref.channels.iter().any(|a| a == channel)
+                && !client_ref.muted_channels.iter().any(|a| a == channel);
+            if listening && client_ref.role >= min_role {
+                client_ref.send(message.clone());
+            }
+        }
+    }
+
+    pub async fn enter_room(&self, client: &ClientPointer, addr: &RoomAddr) {
+        let client_addr = client.lock().await.addr;
+        if let (Some(room), Some(client_addr)) = (self.get_room(addr).await, client_addr) {
+            room.lock().await.clients.insert(client_addr);
+        }
+    }
+
+    pub async fn leave_room(&self, client: &ClientPointer, addr: &RoomAddr) {
+        let client_addr = client.lock().await.addr;
+        if let (Some(room), Some(client_addr)) = (self.get_room(addr).await, client_addr) {
+            room.lock().await.clients.remove(&client_addr);
+        }
+    }
+
+    // Puts a freshly logged in player into their room, or the nexus if it is gone
+    pub async fn arrive(&self, client: &ClientPointer) {
+        let (name, mut room, hidden) = {
+            let client_ref = client.lock().await;
+            (client_ref.name.clone(), client_ref.current_room.clone(), self.is_hidden(&client_ref))
+        };
+        if self.get_room(&room).await.is_none() {
+            room = "nexus".into();
+            client.lock().await.current_room = room.clone();
+        }
+        self.enter_room(client, &room).await;
+        if !hidden {
+            self.send_to_room(&room, format!{"@C{} appears.", name}, Some(client)).await;
+        }
+    }
+
+    // Straight to a room without using links, to anyone watching they vanish and appear
+    pub async fn teleport(&self, client: &ClientPointer, to: &RoomAddr) {
+        self.depart(client).await;
+        client.lock().await.current_room = to.clone();
+        self.arrive(client).await;
+    }
+
+    pub async fn depart(&self, client: &ClientPointer) {
+        let (name, room, hidden) = {
+            let client_ref = client.lock().await;
+            (client_ref.name.clone(), client_ref.current_room.clone(), self.is_hidden(&client_ref))
+        };
+        self.leave_room(client, &room).await;
+        if !hidden {
+            self.send_to_room(&room, format!{"@C{} disappears.", name}, Some(client)).await;
+        }
+    }
 }
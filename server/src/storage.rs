@@ -0,0 +1,519 @@
+use std::{collections::HashMap, fs::{File, OpenOptions}, io::{BufReader, BufWriter, ErrorKind, Write}, path::{Path, PathBuf}, sync::Mutex, time::{SystemTime, UNIX_EPOCH}};
+
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::{config::{ServerConfig, StoreKind}, items::ItemTemplate, states::{ClientState, Room, RoomAddr}, timers::Timer};
+
+// Everything the server persists goes through one of these, pick one with "store" in config.json
+pub trait WorldStore: Send + Sync {
+    fn load_rooms(&self) -> std::io::Result<HashMap<RoomAddr, Room>>;
+    // Rooms not in the map are left alone
+    fn save_rooms(&self, rooms: &HashMap<RoomAddr, Room>) -> std::io::Result<()>;
+    fn load_account(&self, name: &str) -> std::io::Result<Option<ClientState>>;
+    fn save_account(&self, client: &ClientState) -> std::io::Result<()>;
+    fn load_templates(&self) -> std::io::Result<HashMap<String, ItemTemplate>>;
+    // Replaces every saved template, there are never many
+    fn save_templates(&self, templates: &HashMap<String, ItemTemplate>) -> std::io::Result<()>;
+    fn load_timers(&self) -> std::io::Result<Vec<Timer>>;
+    // Replaces every saved timer
+    fn save_timers(&self, timers: &[Timer]) -> std::io::Result<()>;
+}
+
+pub fn open_store(config: &ServerConfig) -> std::io::Result<Box<dyn WorldStore>> {
+    Ok(match config.store {
+        StoreKind::Json => Box::new(JsonStore::new(&config.database_dir, config.world_backups)?),
+        StoreKind::Sqlite => Box::new(SqliteStore::open(PathBuf::from(&config.database_dir).join("world.sqlite"))?),
+        StoreKind::Memory => Box::new(MemoryStore::default()),
+    })
+}
+
+// Writes next to the target then renames over it, so a crash mid save leaves the old file intact
+fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+
+    let file = OpenOptions::new().create(true).write(true).truncate(true).open(&temp_path)?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(contents)?;
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    std::fs::rename(&temp_path, path)?;
+
+    // The rename itself only sticks once the directory entry is flushed
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+fn read_world(path: &Path) -> std::io::Result<HashMap<RoomAddr, Room>> {
+    let file = OpenOptions::new().read(true).open(path)?;
+    Ok(serde_json::from_reader(BufReader::new(file))?)
+}
+
+// The store's own files, anything else at the top of the database dir is from before accounts had players/
+const JSON_STORE_FILES: &[&str] = &["world", "templates", "timers"];
+
+// database/world.json for rooms and database/players/<name>.json per player, kept apart so no name can
+// land on the store's own files
+pub struct JsonStore {
+    dir: PathBuf,
+    backups: usize,
+    // Saves of the same file share a temp file, so only one save may run at a time
+    save_lock: Mutex<()>
+}
+
+impl JsonStore {
+    pub fn new(dir: &str, backups: usize) -> std::io::Result<Self> {
+        std::fs::create_dir_all(PathBuf::from(dir).join("backups"))?;
+        std::fs::create_dir_all(PathBuf::from(dir).join("players"))?;
+        let store = Self { dir: PathBuf::from(dir), backups, save_lock: Mutex::new(()) };
+        store.move_old_accounts()?;
+        Ok(store)
+    }
+
+    // Accounts used to sit next to world.json
+    fn move_old_accounts(&self) -> std::io::Result<()> {
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if !path.is_file() || path.extension().and_then(|a| a.to_str()) != Some("json") {
+                continue;
+            }
+            let name = match path.file_stem().and_then(|a| a.to_str()) {
+                Some(name) if !JSON_STORE_FILES.contains(&name) => name,
+                _ => continue
+            };
+            if !self.account_path(name).exists() {
+                std::fs::rename(&path, self.account_path(name))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn world_path(&self) -> PathBuf {
+        self.dir.join("world.json")
+    }
+
+    fn templates_path(&self) -> PathBuf {
+        self.dir.join("templates.json")
+    }
+
+    fn timers_path(&self) -> PathBuf {
+        self.dir.join("timers.json")
+    }
+
+    fn account_path(&self, name: &str) -> PathBuf {
+        self.dir.join("players").join(format!{"{}.json", name})
+    }
+
+    // Newest first, named world-<unix millis>.json
+    fn backup_paths(&self) -> std::io::Result<Vec<(u128, PathBuf)>> {
+        let mut backups = vec![];
+        for entry in std::fs::read_dir(self.dir.join("backups"))? {
+            let path = entry?.path();
+            let stamp = path.file_name()
+                .and_then(|a| a.to_str())
+                .and_then(|a| a.strip_prefix("world-"))
+                .and_then(|a| a.strip_suffix(".json"))
+                .and_then(|a| a.parse::<u128>().ok());
+            if let Some(stamp) = stamp {
+                backups.push((stamp, path));
+            }
+        }
+        backups.sort_by_key(|a| std::cmp::Reverse(a.0));
+        Ok(backups)
+    }
+
+    // Copies the current world.json into backups/ and drops the oldest ones past the limit
+    fn rotate_backups(&self) -> std::io::Result<()> {
+        // Never rotate a broken world.json in over the good backups
+        if self.backups == 0 || read_world(&self.world_path()).is_err() {
+            return Ok(());
+        }
+        let stamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let newest = self.backup_paths()?.first().map(|a| a.0).unwrap_or(0);
+        // Two saves inside the same millisecond should not overwrite each other
+        let stamp = stamp.max(newest + 1);
+        let contents = std::fs::read(self.world_path())?;
+        write_atomic(&self.dir.join("backups").join(format!{"world-{}.json", stamp}), &contents)?;
+
+        for (_, old) in self.backup_paths()?.into_iter().skip(self.backups) {
+            std::fs::remove_file(old)?;
+        }
+        Ok(())
+    }
+}
+
+impl WorldStore for JsonStore {
+    fn load_rooms(&self) -> std::io::Result<HashMap<RoomAddr, Room>> {
+        let primary_error = match read_world(&self.world_path()) {
+            Ok(rooms) => return Ok(rooms),
+            Err(e) => e
+        };
+        let backups = self.backup_paths()?;
+        if primary_error.kind() == ErrorKind::NotFound && backups.is_empty() {
+            // Fresh database
+            return Ok(HashMap::new());
+        }
+
+        eprintln!("Could not read {}: {}", self.world_path().display(), primary_error);
+        for (_, backup) in backups {
+            match read_world(&backup) {
+                Ok(rooms) => {
+                    eprintln!("Loaded world from backup {}", backup.display());
+                    return Ok(rooms);
+                },
+                Err(e) => eprintln!("Skipping backup {}: {}", backup.display(), e)
+            }
+        }
+        Err(std::io::Error::new(ErrorKind::InvalidData,
+            format!{"{} is unreadable and no valid backup was found", self.world_path().display()}))
+    }
+
+    fn save_rooms(&self, rooms: &HashMap<RoomAddr, Room>) -> std::io::Result<()> {
+        let _guard = self.save_lock.lock().unwrap();
+        // One file holds every room so merge with whatever is already saved
+        let mut world = self.load_rooms()?;
+        world.extend(rooms.iter().map(|(a, b)| (a.clone(), b.clone())));
+        let contents = serde_json::to_vec(&world)?;
+        self.rotate_backups()?;
+        write_atomic(&self.world_path(), &contents)
+    }
+
+    fn load_account(&self, name: &str) -> std::io::Result<Option<ClientState>> {
+        let file = OpenOptions::new().read(true).open(self.account_path(name));
+        match file {
+            Ok(file) => Ok(Some(serde_json::from_reader(BufReader::new(file))?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    fn save_account(&self, client: &ClientState) -> std::io::Result<()> {
+        let _guard = self.save_lock.lock().unwrap();
+        write_atomic(&self.account_path(&client.name), &serde_json::to_vec(client)?)
+    }
+
+    fn load_templates(&self) -> std::io::Result<HashMap<String, ItemTemplate>> {
+        match OpenOptions::new().read(true).open(self.templates_path()) {
+            Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(e) => Err(e)
+        }
+    }
+
+    fn save_templates(&self, templates: &HashMap<String, ItemTemplate>) -> std::io::Result<()> {
+        let _guard = self.save_lock.lock().unwrap();
+        write_atomic(&self.templates_path(), &serde_json::to_vec(templates)?)
+    }
+
+    fn load_timers(&self) -> std::io::Result<Vec<Timer>> {
+        match OpenOptions::new().read(true).open(self.timers_path()) {
+            Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e)
+        }
+    }
+
+    fn save_timers(&self, timers: &[Timer]) -> std::io::Result<()> {
+        let _guard = self.save_lock.lock().unwrap();
+        write_atomic(&self.timers_path(), &serde_json::to_vec(timers)?)
+    }
+}
+
+// Accounts are kept serialized so loading hands back a fresh ClientState like the disk stores do
+#[derive(Default)]
+pub struct MemoryStore {
+    rooms: Mutex<HashMap<RoomAddr, Room>>,
+    accounts: Mutex<HashMap<String, String>>,
+    templates: Mutex<HashMap<String, ItemTemplate>>,
+    timers: Mutex<Vec<Timer>>
+}
+
+impl WorldStore for MemoryStore {
+    fn load_rooms(&self) -> std::io::Result<HashMap<RoomAddr, Room>> {
+        Ok(self.rooms.lock().unwrap().clone())
+    }
+
+    fn save_rooms(&self, rooms: &HashMap<RoomAddr, Room>) -> std::io::Result<()> {
+        self.rooms.lock().unwrap().extend(rooms.iter().map(|(a, b)| (a.clone(), b.clone())));
+        Ok(())
+    }
+
+    fn load_account(&self, name: &str) -> std::io::Result<Option<ClientState>> {
+        match self.accounts.lock().unwrap().get(name) {
+            Some(data) => Ok(Some(serde_json::from_str(data)?)),
+            None => Ok(None)
+        }
+    }
+
+    fn save_account(&self, client: &ClientState) -> std::io::Result<()> {
+        let data = serde_json::to_string(client)?;
+        self.accounts.lock().unwrap().insert(client.name.clone(), data);
+        Ok(())
+    }
+
+    fn load_templates(&self) -> std::io::Result<HashMap<String, ItemTemplate>> {
+        Ok(self.templates.lock().unwrap().clone())
+    }
+
+    fn save_templates(&self, templates: &HashMap<String, ItemTemplate>) -> std::io::Result<()> {
+        *self.templates.lock().unwrap() = templates.clone();
+        Ok(())
+    }
+
+    fn load_timers(&self) -> std::io::Result<Vec<Timer>> {
+        Ok(self.timers.lock().unwrap().clone())
+    }
+
+    fn save_timers(&self, timers: &[Timer]) -> std::io::Result<()> {
+        *self.timers.lock().unwrap() = timers.to_vec();
+        Ok(())
+    }
+}
+
+// One row per room so a save only touches the rooms handed to it
+pub struct SqliteStore {
+    connection: Mutex<Connection>
+}
+
+fn sql_error(e: rusqlite::Error) -> std::io::Error {
+    std::io::Error::other(e)
+}
+
+impl SqliteStore {
+    pub fn open(path: PathBuf) -> std::io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let connection = Connection::open(path).map_err(sql_error)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS rooms (addr TEXT PRIMARY KEY, data TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS accounts (name TEXT PRIMARY KEY, data TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS templates (name TEXT PRIMARY KEY, data TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS timers (handle INTEGER PRIMARY KEY, data TEXT NOT NULL);"
+        ).map_err(sql_error)?;
+        Ok(Self { connection: Mutex::new(connection) })
+    }
+}
+
+impl WorldStore for SqliteStore {
+    fn load_rooms(&self) -> std::io::Result<HashMap<RoomAddr, Room>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT addr, data FROM rooms").map_err(sql_error)?;
+        let rows = statement.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+            .map_err(sql_error)?;
+        let mut rooms = HashMap::new();
+        for row in rows {
+            let (addr, data) = row.map_err(sql_error)?;
+            rooms.insert(addr, serde_json::from_str(&data)?);
+        }
+        Ok(rooms)
+    }
+
+    fn save_rooms(&self, rooms: &HashMap<RoomAddr, Room>) -> std::io::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction().map_err(sql_error)?;
+        for (addr, room) in rooms.iter() {
+            transaction.execute(
+                "INSERT INTO rooms (addr, data) VALUES (?1, ?2) ON CONFLICT(addr) DO UPDATE SET data = excluded.data",
+                params![addr, serde_json::to_string(room)?]
+            ).map_err(sql_error)?;
+        }
+        transaction.commit().map_err(sql_error)
+    }
+
+    fn load_account(&self, name: &str) -> std::io::Result<Option<ClientState>> {
+        let connection = self.connection.lock().unwrap();
+        let data: Option<String> = connection
+            .query_row("SELECT data FROM accounts WHERE name = ?1", params![name], |row| row.get(0))
+            .optional()
+            .map_err(sql_error)?;
+        match data {
+            Some(data) => Ok(Some(serde_json::from_str(&data)?)),
+            None => Ok(None)
+        }
+    }
+
+    fn save_account(&self, client: &ClientState) -> std::io::Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT INTO accounts (name, data) VALUES (?1, ?2) ON CONFLICT(name) DO UPDATE SET data = excluded.data",
+            params![client.name, serde_json::to_string(client)?]
+        ).map_err(sql_error)?;
+        Ok(())
+    }
+
+    fn load_templates(&self) -> std::io::Result<HashMap<String, ItemTemplate>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT name, data FROM templates").map_err(sql_error)?;
+        let rows = statement.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+            .map_err(sql_error)?;
+        let mut templates = HashMap::new();
+        for row in rows {
+            let (name, data) = row.map_err(sql_error)?;
+            templates.insert(name, serde_json::from_str(&data)?);
+        }
+        Ok(templates)
+    }
+
+    fn save_templates(&self, templates: &HashMap<String, ItemTemplate>) -> std::io::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction().map_err(sql_error)?;
+        transaction.execute("DELETE FROM templates", []).map_err(sql_error)?;
+        for (name, template) in templates.iter() {
+            transaction.execute(
+                "INSERT INTO templates (name, data) VALUES (?1, ?2)",
+                params![name, serde_json::to_string(template)?]
+            ).map_err(sql_error)?;
+        }
+        transaction.commit().map_err(sql_error)
+    }
+
+    fn load_timers(&self) -> std::io::Result<Vec<Timer>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT data FROM timers ORDER BY handle").map_err(sql_error)?;
+        let rows = statement.query_map([], |row| row.get::<_, String>(0)).map_err(sql_error)?;
+        let mut timers = vec![];
+        for row in rows {
+            timers.push(serde_json::from_str(&row.map_err(sql_error)?)?);
+        }
+        Ok(timers)
+    }
+
+    fn save_timers(&self, timers: &[Timer]) -> std::io::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction().map_err(sql_error)?;
+        transaction.execute("DELETE FROM timers", []).map_err(sql_error)?;
+        for timer in timers.iter() {
+            transaction.execute(
+                "INSERT INTO timers (handle, data) VALUES (?1, ?2)",
+                params![timer.handle as i64, serde_json::to_string(timer)?]
+            ).map_err(sql_error)?;
+        }
+        transaction.commit().map_err(sql_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timers::TimerAction;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!{"store-test-{}-{}", name, std::process::id()});
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn room(addr: &str, display: &str) -> Room {
+        Room { addr: addr.into(), display: display.into(), ..Default::default() }
+    }
+
+    fn timer(handle: u64) -> Timer {
+        Timer { handle, due: 100, player: "alice".into(), script: "flower".into(), action: TimerAction::Message("hi".into()) }
+    }
+
+    // Writes one of everything, then checks it reads back and that later saves replace or merge as promised
+    fn save_everything(store: &dyn WorldStore) {
+        assert!(store.load_rooms().unwrap().is_empty());
+        store.save_rooms(&HashMap::from([("nexus".into(), room("nexus", "old")), ("cave".into(), room("cave", "dark"))])).unwrap();
+        store.save_rooms(&HashMap::from([("nexus".into(), room("nexus", "new"))])).unwrap();
+
+        assert!(store.load_account("alice").unwrap().is_none());
+        let mut alice = ClientState::new(None);
+        alice.name = "alice".into();
+        alice.password_hash = "hash".into();
+        alice.resources.insert("coins".into(), 12);
+        store.save_account(&alice).unwrap();
+
+        store.save_templates(&HashMap::from([
+            ("sword".into(), ItemTemplate::new("sword".into(), 1, 2)),
+            ("shield".into(), ItemTemplate::new("shield".into(), 3, 4))
+        ])).unwrap();
+        store.save_templates(&HashMap::from([("sword".into(), ItemTemplate::new("sword".into(), 5, 6))])).unwrap();
+
+        store.save_timers(&[timer(1), timer(2)]).unwrap();
+        store.save_timers(&[timer(3)]).unwrap();
+    }
+
+    fn check_everything(store: &dyn WorldStore) {
+        let rooms = store.load_rooms().unwrap();
+        assert_eq!(rooms.len(), 2);
+        assert_eq!(rooms["nexus"].display, "new");
+        assert_eq!(rooms["cave"].display, "dark");
+
+        let alice = store.load_account("alice").unwrap().unwrap();
+        assert_eq!(alice.name, "alice");
+        assert_eq!(alice.password_hash, "hash");
+        assert_eq!(alice.resources["coins"], 12);
+
+        let templates = store.load_templates().unwrap();
+        assert_eq!(templates.len(), 1);
+        assert_eq!(templates["sword"].difficulty, 5);
+
+        let timers = store.load_timers().unwrap();
+        assert_eq!(timers.iter().map(|a| a.handle).collect::<Vec<_>>(), vec![3]);
+    }
+
+    #[test]
+    fn memory_store_round_trip() {
+        let store = MemoryStore::default();
+        save_everything(&store);
+        check_everything(&store);
+    }
+
+    #[test]
+    fn json_store_round_trip() {
+        let dir = temp_dir("json");
+        save_everything(&JsonStore::new(dir.to_str().unwrap(), 2).unwrap());
+        check_everything(&JsonStore::new(dir.to_str().unwrap(), 2).unwrap());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn json_accounts_never_overwrite_store_files() {
+        let dir = temp_dir("names");
+        let store = JsonStore::new(dir.to_str().unwrap(), 2).unwrap();
+        store.save_templates(&HashMap::from([("sword".into(), ItemTemplate::new("sword".into(), 1, 2))])).unwrap();
+        for name in ["templates", "timers"] {
+            let mut client = ClientState::new(None);
+            client.name = name.into();
+            store.save_account(&client).unwrap();
+        }
+        store.save_timers(&[timer(1)]).unwrap();
+
+        assert_eq!(store.load_templates().unwrap().len(), 1);
+        assert_eq!(store.load_timers().unwrap().len(), 1);
+        assert_eq!(store.load_account("templates").unwrap().unwrap().name, "templates");
+        assert_eq!(store.load_account("timers").unwrap().unwrap().name, "timers");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn json_store_moves_old_accounts() {
+        let dir = temp_dir("old");
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut mikey = ClientState::new(None);
+        mikey.name = "mikey".into();
+        std::fs::write(dir.join("mikey.json"), serde_json::to_vec(&mikey).unwrap()).unwrap();
+        std::fs::write(dir.join("world.json"), "{}").unwrap();
+        std::fs::write(dir.join("timers.json"), "[]").unwrap();
+
+        let store = JsonStore::new(dir.to_str().unwrap(), 2).unwrap();
+        assert_eq!(store.load_account("mikey").unwrap().unwrap().name, "mikey");
+        assert!(!dir.join("mikey.json").exists());
+        assert!(dir.join("world.json").exists());
+        assert!(store.load_timers().unwrap().is_empty());
+        assert!(!dir.join("players").join("timers.json").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn sqlite_store_round_trip() {
+        let dir = temp_dir("sqlite");
+        save_everything(&SqliteStore::open(dir.join("world.sqlite")).unwrap());
+        check_everything(&SqliteStore::open(dir.join("world.sqlite")).unwrap());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
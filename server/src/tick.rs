@@ -0,0 +1,111 @@
+use std::{future::Future, pin::Pin, sync::Arc, time::{Duration, Instant}};
+
+use tokio::time::MissedTickBehavior;
+
+use crate::states::ServerState;
+
+pub type TickFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+pub type TickFn = fn(Arc<ServerState>) -> TickFuture;
+
+// Turns an `async fn(&Arc<ServerState>)` into a TickFn
+macro_rules! tick_handler {
+    ($f:path) => {
+        |server_state: Arc<ServerState>| -> TickFuture {
+            Box::pin(async move { $f(&server_state).await })
+        }
+    }
+}
+
+struct Subscriber {
+    name: &'static str,
+    // Runs on every this many ticks
+    every: u64,
+    handler: TickFn
+}
+
+// One loop for everything that happens on its own, handlers run in the order they subscribed
+pub struct Scheduler {
+    period: Duration,
+    subscribers: Vec<Subscriber>
+}
+
+impl Scheduler {
+    pub fn new(period: Duration) -> Self {
+        Self { period: period.max(Duration::from_millis(1)), subscribers: vec![] }
+    }
+
+    // every is rounded to whole ticks, never less than one
+    pub fn subscribe(&mut self, name: &'static str, every: Duration, handler: TickFn) {
+        let every = (every.as_millis() / self.period.as_millis()).max(1) as u64;
+        self.subscribers.push(Subscriber { name, every, handler });
+    }
+
+    // Who runs on tick number count
+    fn due(&self, count: u64) -> impl Iterator<Item = &Subscriber> {
+        self.subscribers.iter().filter(move |a| count.is_multiple_of(a.every))
+    }
+
+    pub async fn run(self, server_state: Arc<ServerState>) {
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + self.period, self.period);
+        // A slow tick pushes the next ones back instead of firing a burst to catch up
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut count: u64 = 0;
+        loop {
+            interval.tick().await;
+            count += 1;
+            let started = Instant::now();
+            let mut timings = vec![];
+            for subscriber in self.due(count) {
+                let handler_started = Instant::now();
+                (subscriber.handler)(server_state.clone()).await;
+                timings.push((subscriber.name, handler_started.elapsed()));
+            }
+            if let Some(warning) = overrun(count, self.period, started.elapsed(), timings) {
+                eprintln!("{}", warning);
+            }
+        }
+    }
+}
+
+// What gets logged when a tick took longer than its period, slowest handlers first
+fn overrun(count: u64, period: Duration, took: Duration, mut timings: Vec<(&str, Duration)>) -> Option<String> {
+    if took <= period {
+        return None;
+    }
+    timings.sort_by_key(|a| std::cmp::Reverse(a.1));
+    let slowest: Vec<String> = timings.iter().map(|(name, time)| format!{"{} {}ms", name, time.as_millis()}).collect();
+    Some(format!{"Tick {} overran its {}ms budget, took {}ms ({})", count, period.as_millis(), took.as_millis(), slowest.join(", ")})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn nothing(_server_state: &Arc<ServerState>) {}
+
+    fn names(scheduler: &Scheduler, count: u64) -> Vec<&'static str> {
+        scheduler.due(count).map(|a| a.name).collect()
+    }
+
+    #[test]
+    fn handlers_run_on_their_own_cadence() {
+        let mut scheduler = Scheduler::new(Duration::from_millis(250));
+        scheduler.subscribe("fast", Duration::from_millis(100), tick_handler!(nothing));
+        scheduler.subscribe("second", Duration::from_secs(1), tick_handler!(nothing));
+        scheduler.subscribe("five", Duration::from_secs(5), tick_handler!(nothing));
+        assert_eq!(scheduler.subscribers.iter().map(|a| a.every).collect::<Vec<_>>(), vec![1, 4, 20]);
+        assert_eq!(names(&scheduler, 1), vec!["fast"]);
+        assert_eq!(names(&scheduler, 4), vec!["fast", "second"]);
+        assert_eq!(names(&scheduler, 20), vec!["fast", "second", "five"]);
+        assert_eq!((1..=100).filter(|a| names(&scheduler, *a).contains(&"second")).count(), 25);
+    }
+
+    #[test]
+    fn only_overruns_are_logged_slowest_first() {
+        let period = Duration::from_millis(250);
+        assert_eq!(overrun(3, period, Duration::from_millis(250), vec![("npc behaviour", Duration::from_millis(200))]), None);
+        let timings = vec![("regeneration", Duration::from_millis(20)), ("npc behaviour", Duration::from_millis(300))];
+        assert_eq!(overrun(3, period, Duration::from_millis(320), timings).unwrap(),
+            "Tick 3 overran its 250ms budget, took 320ms (npc behaviour 300ms, regeneration 20ms)");
+    }
+}
@@ -0,0 +1,157 @@
+use std::sync::{Arc, atomic::{AtomicU64, Ordering}};
+
+use dyon::Variable;
+use serde_derive::{Serialize, Deserialize};
+
+use crate::{dyon_inter, script_api::{self, ScriptEffect}, states::{ClientPointer, ServerState, unix_now}};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum TimerAction {
+    // Calls fn <name>(state) in the script that set the timer, a str it returns goes to the player
+    Call(String),
+    Message(String)
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Timer {
+    pub handle: u64,
+    // Unix seconds
+    pub due: u64,
+    pub player: String,
+    pub script: String,
+    pub action: TimerAction
+}
+
+// What a script asked for while it ran, applied once the call returns
+#[derive(Serialize, Deserialize)]
+pub enum TimerRequest {
+    Start { handle: u64, seconds: u64, action: TimerAction },
+    Cancel(u64)
+}
+
+// Handles start from the clock so one kept by a script from before a restart never matches a newer timer
+static NEXT_HANDLE: AtomicU64 = AtomicU64::new(0);
+
+pub fn resume_handles(timers: &[Timer]) {
+    let next = timers.iter().map(|a| a.handle + 1).max().unwrap_or(0).max(unix_now() * 1000);
+    NEXT_HANDLE.fetch_max(next, Ordering::SeqCst);
+}
+
+// Scripts run in worker processes, each run hands out handles from a block the server set aside for it
+pub fn reserve_handles(count: u64) -> u64 {
+    NEXT_HANDLE.fetch_add(count, Ordering::SeqCst)
+}
+
+pub fn start_handles_at(first: u64) {
+    NEXT_HANDLE.store(first, Ordering::SeqCst);
+}
+
+// Longest delay a script can ask for, a worker sets it from script_max_timer_secs before running anything
+static MAX_DELAY: AtomicU64 = AtomicU64::new(u64::MAX);
+
+pub fn set_max_delay(seconds: u64) {
+    MAX_DELAY.store(seconds, Ordering::SeqCst);
+}
+
+// Negative and NaN delays fire straight away
+fn clamp_delay(seconds: f64, max: u64) -> u64 {
+    (seconds.max(0.0) as u64).min(max)
+}
+
+pub fn request(seconds: f64, action: TimerAction) -> u64 {
+    let handle = NEXT_HANDLE.fetch_add(1, Ordering::SeqCst);
+    let seconds = clamp_delay(seconds, MAX_DELAY.load(Ordering::SeqCst));
+    script_api::push(ScriptEffect::Timer(TimerRequest::Start { handle, seconds, action }));
+    handle
+}
+
+pub fn request_cancel(handle: u64) {
+    script_api::push(ScriptEffect::Timer(TimerRequest::Cancel(handle)));
+}
+
+// Starts and cancels what a script run for player asked for, scripts can only cancel their player's timers
+pub fn apply(server_state: &ServerState, player: &str, script: &str, requests: Vec<TimerRequest>) {
+    let mut timers = server_state.timers.lock().unwrap();
+    for request in requests {
+        match request {
+            TimerRequest::Start { handle, seconds, action } => timers.push(Timer {
+                handle,
+                due: unix_now().saturating_add(seconds),
+                player: player.into(),
+                script: script.into(),
+                action
+            }),
+            TimerRequest::Cancel(handle) => timers.retain(|a| a.handle != handle || a.player != player)
+        }
+    }
+}
+
+async fn run_callback(server_state: &ServerState, timer: &Timer, function: &str, client: &ClientPointer) -> std::io::Result<Option<String>> {
+    let label = format!{"timer {} for {}", timer.handle, timer.player};
+    let (result, effects) = dyon_inter::call_with_state(&format!{"dyon/{}.dyon", timer.script}, function, client.clone(), server_state, &label).await
+        .map_err(std::io::Error::other)?;
+    script_api::apply(server_state, client, &timer.script, effects).await;
+    match result {
+        Some(Variable::Str(text)) if !text.is_empty() => Ok(Some((*text).clone())),
+        _ => Ok(None)
+    }
+}
+
+// Fires every due timer whose player is online, the rest wait until they log back in
+pub async fn fire(server_state: &Arc<ServerState>) {
+    let now = unix_now();
+    let due: Vec<Timer> = server_state.timers.lock().unwrap().iter().filter(|a| a.due <= now).cloned().collect();
+    for timer in due {
+        let client = match server_state.find_player(&timer.player).await {
+            Some(client) => client,
+            None => continue
+        };
+        {
+            let mut timers = server_state.timers.lock().unwrap();
+            let before = timers.len();
+            timers.retain(|a| a.handle != timer.handle);
+            // Gone already if a callback that ran before it cancelled it
+            if timers.len() == before {
+                continue;
+            }
+        }
+
+        let text = match &timer.action {
+            TimerAction::Message(text) => Some(text.clone()),
+            TimerAction::Call(function) => match run_callback(server_state, &timer, function, &client).await {
+                Ok(text) => text,
+                Err(e) => {
+                    eprintln!("Timer {} calling {} in {} failed for {}: {}", timer.handle, function, timer.script, timer.player, e);
+                    None
+                }
+            }
+        };
+        if let Some(text) = text {
+            client.lock().await.send(text);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::ServerConfig, storage::MemoryStore};
+
+    #[test]
+    fn delays_are_capped() {
+        assert_eq!(clamp_delay(1e30, 3600), 3600);
+        assert_eq!(clamp_delay(f64::INFINITY, 3600), 3600);
+        assert_eq!(clamp_delay(90.5, 3600), 90);
+        assert_eq!(clamp_delay(-5.0, 3600), 0);
+        assert_eq!(clamp_delay(f64::NAN, 3600), 0);
+    }
+
+    #[test]
+    fn huge_delays_do_not_overflow() {
+        let server_state = ServerState::new(ServerConfig { script_workers: 1, ..Default::default() }, Box::new(MemoryStore::default())).unwrap();
+        let start = TimerRequest::Start { handle: 1, seconds: u64::MAX, action: TimerAction::Message("hi".into()) };
+        apply(&server_state, "bob", "test", vec![start]);
+        let timers = server_state.timers.lock().unwrap();
+        assert_eq!(timers[0].due, u64::MAX);
+    }
+}
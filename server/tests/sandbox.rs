@@ -0,0 +1,51 @@
+// Scripts run in worker processes started from the server binary, so these go through the built
+// binary's run-script instead of the unit tests, whose own executable is the test harness
+use std::{path::PathBuf, process::Command, time::{Duration, Instant}};
+
+// Runs fn home from script in a fresh directory with the default limits, returns what run-script printed
+fn run(name: &str, script: &str) -> String {
+    let dir: PathBuf = std::env::temp_dir().join(format!{"sandbox-test-{}-{}", std::process::id(), name});
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("script.dyon"), script).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_server"))
+        .args(["run-script", "script.dyon"])
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+    assert!(output.status.success(), "run-script failed: {}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8_lossy(&output.stdout).into()
+}
+
+#[test]
+fn scripts_run() {
+    let output = run("runs", "fn home(state) -> str {\n  tell(\"hi\")\n  return \"done\"\n}\n");
+    assert!(output.starts_with("Returned Some(Str(\"done\")) and asked for 1 "), "{}", output);
+}
+
+#[test]
+fn infinite_loops_are_killed() {
+    let start = Instant::now();
+    let output = run("loop", "fn home(state) -> str {\n  x := 0\n  loop { x += 1 }\n  return \"no\"\n}\n");
+    assert_eq!(output.trim(), "Stopped, it ran for longer than 500ms");
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn memory_hogs_are_stopped() {
+    let output = run("hog", "fn home(state) -> str {\n  s := \"a\"\n  loop { s = s + s }\n  return \"no\"\n}\n");
+    assert!(output.starts_with("Stopped, it used more than 16384kb of memory"), "{}", output);
+}
+
+#[test]
+fn deep_recursion_is_stopped() {
+    let output = run("deep", "fn deep(n: f64) -> f64 {\n  return deep(n + 1) + 1\n}\n\nfn home(state) -> str {\n  return str(deep(0))\n}\n");
+    assert!(output.starts_with("Stopped, "), "{}", output);
+    assert!(output.contains("recursed too deeply"), "{}", output);
+}
+
+#[test]
+fn effects_are_capped() {
+    let output = run("effects", "fn home(state) -> str {\n  loop { tell(\"hi\") }\n  return \"no\"\n}\n");
+    assert_eq!(output.trim(), "Stopped, it asked for more than 100 tells, moves, objects and timers");
+}